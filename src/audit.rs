@@ -0,0 +1,344 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use thiserror::Error;
+
+// The hash preceding the first record in the chain
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+// The action of the record starting a new chain after a broken one
+const CHAIN_BREAK: &str = "chain break";
+
+/// A single administrative action. Each record carries the hash of
+/// the one before it so that editing or removing a line breaks the chain.
+/// The hashes are not keyed, so anyone able to write the file can rewrite it
+/// whole or cut records off its end without breaking the chain.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Record {
+    pub seq: u64,
+    /// Seconds since the UNIX epoch
+    pub time: u64,
+    pub actor: String,
+    pub fingerprint: String,
+    pub action: String,
+    pub target: String,
+    pub outcome: String,
+    pub prev: String,
+    pub hash: String,
+}
+
+impl Record {
+    fn digest(&self) -> String {
+        let mut hasher = Sha256::new();
+        for field in [
+            self.prev.as_str(),
+            &self.seq.to_string(),
+            &self.time.to_string(),
+            &self.actor,
+            &self.fingerprint,
+            &self.action,
+            &self.target,
+            &self.outcome,
+        ] {
+            hasher.update(field.as_bytes());
+            // separate fields so that their boundaries are part of the hash
+            hasher.update([0]);
+        }
+        hex::encode(hasher.finalize())
+    }
+}
+
+impl Record {
+    /// Whether the record starts a new chain after a broken one.
+    pub fn is_break(&self) -> bool {
+        self.action == CHAIN_BREAK && self.seq == 1
+    }
+}
+
+impl std::fmt::Display for Record {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "#{} {} {} ({}) {} {}: {}",
+            self.seq,
+            format_time(self.time),
+            self.actor,
+            self.fingerprint,
+            self.action,
+            self.target,
+            self.outcome
+        )
+    }
+}
+
+/// Formats seconds since the UNIX epoch as `YYYY-MM-DD HH:MM:SS UTC`.
+fn format_time(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let time = secs % 86400;
+
+    // civil date from days since the epoch, after Howard Hinnant's algorithm
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+        time / 3600,
+        (time / 60) % 60,
+        time % 60
+    )
+}
+
+/// The append-only audit log of administrative actions.
+pub struct AuditLog {
+    path: PathBuf,
+    next_seq: u64,
+    last_hash: String,
+    // Set once a new process has taken over the log on restart
+    handed_over: bool,
+}
+
+impl AuditLog {
+    /// Opens the log at the path, creating it if needed, and verifies its chain.
+    /// A broken chain is returned along with the log, which carries on with
+    /// a new chain starting with a record of the break.
+    pub fn open(path: &Path) -> Result<(Self, Option<Error>), Error> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let records = if path.exists() {
+            read(path)
+        } else {
+            Ok(vec![])
+        };
+        let mut log = Self {
+            path: path.to_path_buf(),
+            next_seq: 1,
+            last_hash: GENESIS.to_string(),
+            handed_over: false,
+        };
+        match records.and_then(|records| verify(&records).map(|()| records)) {
+            Ok(records) => {
+                if let Some(last) = records.last() {
+                    log.next_seq = last.seq + 1;
+                    log.last_hash = last.hash.clone();
+                }
+                Ok((log, None))
+            }
+            Err(Error::Io(e)) => Err(Error::Io(e)),
+            Err(broken) => {
+                // a blank line keeps the record apart from a partly written last line
+                OpenOptions::new()
+                    .append(true)
+                    .open(path)?
+                    .write_all(b"\n")?;
+                log.append("server", "-", CHAIN_BREAK, "audit log", &broken.to_string())?;
+                Ok((log, Some(broken)))
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Stops appending to the log, which a new process has taken over and
+    /// carries on from its own view of the chain.
+    pub fn hand_over(&mut self) {
+        self.handed_over = true;
+    }
+
+    pub fn append(
+        &mut self,
+        actor: &str,
+        fingerprint: &str,
+        action: &str,
+        target: &str,
+        outcome: &str,
+    ) -> Result<(), Error> {
+        if self.handed_over {
+            return Err(Error::HandedOver);
+        }
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut record = Record {
+            seq: self.next_seq,
+            time,
+            actor: actor.to_string(),
+            fingerprint: fingerprint.to_string(),
+            action: action.to_string(),
+            target: target.to_string(),
+            outcome: outcome.to_string(),
+            prev: self.last_hash.clone(),
+            hash: String::new(),
+        };
+        record.hash = record.digest();
+
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+
+        self.next_seq += 1;
+        self.last_hash = record.hash;
+        Ok(())
+    }
+}
+
+/// Reads the records of the current chain without checking it,
+/// from the latest break onwards if it was ever broken.
+pub fn read(path: &Path) -> Result<Vec<Record>, Error> {
+    let reader = BufReader::new(std::fs::File::open(path)?);
+    let mut lines = vec![];
+    // lines of a broken chain may not even be text
+    for (number, line) in reader.split(b'\n').enumerate() {
+        let line = String::from_utf8_lossy(&line?).into_owned();
+        if !line.trim().is_empty() {
+            lines.push((number + 1, line));
+        }
+    }
+    let start = lines
+        .iter()
+        .rposition(|(_, line)| serde_json::from_str::<Record>(line).is_ok_and(|r| r.is_break()))
+        .unwrap_or(0);
+
+    let mut records = vec![];
+    for (number, line) in &lines[start..] {
+        let record = serde_json::from_str(line).map_err(|source| Error::Malformed {
+            line: *number,
+            source,
+        })?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Checks that every record links to the one before it and that its hash matches its contents.
+pub fn verify(records: &[Record]) -> Result<(), Error> {
+    let mut prev = GENESIS;
+    for (expected_seq, record) in (1..).zip(records) {
+        if record.prev != prev || record.seq != expected_seq || record.digest() != record.hash {
+            return Err(Error::Tampered(record.seq));
+        }
+        prev = &record.hash;
+    }
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("unable to access audit log")]
+    Io(#[from] std::io::Error),
+    #[error("failed to serialize audit record")]
+    Serialize(#[from] serde_json::Error),
+    #[error("malformed audit record at line {line}")]
+    Malformed {
+        line: usize,
+        source: serde_json::Error,
+    },
+    #[error("audit log has been tampered with at record {0}")]
+    Tampered(u64),
+    #[error("the audit log has been handed over to the new process")]
+    HandedOver,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temporary_log(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("publicly-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_append_and_reopen() {
+        let path = temporary_log("audit-append");
+        let (mut log, _) = AuditLog::open(&path).unwrap();
+        log.append("h@cafe", "SHA256:a", "ban", "bob@work", "ok")
+            .unwrap();
+        drop(log);
+
+        let (mut log, broken) = AuditLog::open(&path).unwrap();
+        assert!(broken.is_none());
+        log.append("h@cafe", "SHA256:a", "commit", "./Authfile", "ok")
+            .unwrap();
+
+        let records = read(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].seq, 2);
+        assert_eq!(records[1].prev, records[0].hash);
+        verify(&records).unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_detects_tampering() {
+        let path = temporary_log("audit-tamper");
+        let (mut log, _) = AuditLog::open(&path).unwrap();
+        for target in ["bob@work", "dri@home", "eve"] {
+            log.append("h@cafe", "SHA256:a", "ban", target, "ok")
+                .unwrap();
+        }
+
+        let mut records = read(&path).unwrap();
+        records[1].target = "someone else".to_string();
+        assert!(matches!(verify(&records), Err(Error::Tampered(2))));
+
+        let mut records = read(&path).unwrap();
+        records.remove(1);
+        assert!(matches!(verify(&records), Err(Error::Tampered(3))));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_restarts_broken_chain() {
+        let path = temporary_log("audit-break");
+        let (mut log, _) = AuditLog::open(&path).unwrap();
+        log.append("h@cafe", "SHA256:a", "ban", "bob@work", "ok")
+            .unwrap();
+        drop(log);
+        // a partly written record
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"seq\":2,")
+            .unwrap();
+
+        let (mut log, broken) = AuditLog::open(&path).unwrap();
+        assert!(matches!(broken, Some(Error::Malformed { line: 2, .. })));
+        log.append("h@cafe", "SHA256:a", "commit", "./Authfile", "ok")
+            .unwrap();
+
+        let records = read(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(records[0].is_break());
+        verify(&records).unwrap();
+        let (_, broken) = AuditLog::open(&path).unwrap();
+        assert!(broken.is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_time(1_709_251_199), "2024-02-29 23:59:59 UTC");
+    }
+}
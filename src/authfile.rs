@@ -1,4 +1,5 @@
 use crate::entity::Entity;
+use crate::sanitize;
 use russh::keys::ssh_key::public::KeyData;
 use std::collections::HashSet;
 use std::io::{BufRead, BufReader};
@@ -8,11 +9,42 @@ use thiserror::Error;
 
 pub async fn read(path: &Path) -> Result<AuthFile, Error> {
     let handle = std::fs::File::open(path)?;
-    let reader = BufReader::new(handle);
-    let mut entities = vec![];
+    parse(BufReader::new(handle)).await
+}
+
+/// Parses the keychain from lines in the Authfile format.
+pub async fn parse(reader: impl BufRead) -> Result<AuthFile, Error> {
+    let mut entities: Vec<Entity> = vec![];
     for line in reader.lines() {
         let line = line?;
-        entities.push(line.parse()?);
+        let mut entity: Entity = line.parse()?;
+
+        // keys listed under the same name belong to one member,
+        // while each key without a name is a member of its own
+        let name = entity.name().await;
+        let role = entity.role().await;
+        if name.is_empty() {
+            entities.push(entity);
+            continue;
+        }
+        for member in entities.iter() {
+            let member_name = member.name().await;
+            if member_name != name {
+                if sanitize::names_collide(&member_name, &name) {
+                    return Err(Error::DuplicateName {
+                        name,
+                        existing: member_name,
+                    });
+                }
+                continue;
+            }
+            if member.role().await != role {
+                return Err(Error::ConflictingRoles(name));
+            }
+            entity.link(member);
+            break;
+        }
+        entities.push(entity);
     }
     let key_pool = build_key_data_pool(&entities);
     let entities = entities.into_iter().map(Arc::new).collect();
@@ -34,11 +66,16 @@ pub enum Error {
     FileNotReadable(#[from] std::io::Error),
     #[error("failed to parse entity: {0}")]
     PublicKeyParsing(#[from] crate::entity::Error),
+    #[error("the keys of {0:?} are listed with different roles")]
+    ConflictingRoles(String),
+    #[error("the name {name:?} differs only in case from {existing:?}")]
+    DuplicateName { name: String, existing: String },
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::entity::Role;
 
     #[tokio::test]
     async fn test_read_nonexistent_file() {
@@ -72,5 +109,44 @@ mod tests {
         for (entity, fingerprint) in authfile.entities.iter().zip(fingerprints) {
             assert_eq!(fingerprint, entity.fingerprint())
         }
+        assert!(!authfile.entities[0].same_identity(&authfile.entities[1]));
+    }
+
+    #[tokio::test]
+    async fn test_authfile_colliding_names() {
+        match read(Path::new("tests/fixtures/colliding_names_authfile")).await {
+            Err(Error::DuplicateName { name, existing }) => {
+                assert_eq!((name.as_str(), existing.as_str()), ("Dri@home", "dri@home"))
+            }
+            _ => panic!("names differing only in case should be rejected"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_authfile_linked_keys() {
+        let authfile = read(Path::new("tests/fixtures/linked_keys_authfile"))
+            .await
+            .expect("failed to read authfile with linked keys");
+
+        let [laptop, desktop] = &authfile.entities[..] else {
+            panic!("expected two entities");
+        };
+        assert!(laptop.same_identity(desktop));
+        laptop.set_name("dri").await;
+        assert_eq!(desktop.name().await, "dri");
+    }
+
+    #[tokio::test]
+    async fn test_authfile_nameless_keys() {
+        let authfile = read(Path::new("tests/fixtures/nameless_keys_authfile"))
+            .await
+            .expect("keys without names should not conflict");
+
+        let [normal, admin] = &authfile.entities[..] else {
+            panic!("expected two entities");
+        };
+        assert!(!normal.same_identity(admin));
+        assert_eq!(normal.role().await, Role::Normal);
+        assert_eq!(admin.role().await, Role::Admin);
     }
 }
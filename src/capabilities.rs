@@ -0,0 +1,261 @@
+use ratatui::style::{Color, Style};
+use ratatui::symbols::border;
+use ratatui::text::Text;
+
+/// Borders drawn with plain ASCII for terminals without Unicode support.
+pub const ASCII_BORDER: border::Set = border::Set {
+    top_left: "+",
+    top_right: "+",
+    bottom_left: "+",
+    bottom_right: "+",
+    vertical_left: "|",
+    vertical_right: "|",
+    horizontal_top: "-",
+    horizontal_bottom: "-",
+};
+
+// Terminals known to lack Unicode line drawing characters
+const ASCII_TERMS: [&str; 3] = ["dumb", "ansi", "linux"];
+
+// The approximate RGB values of the 16 ANSI colours
+const ANSI_COLORS: [(Color, (u8, u8, u8)); 16] = [
+    (Color::Black, (0, 0, 0)),
+    (Color::Red, (205, 0, 0)),
+    (Color::Green, (0, 205, 0)),
+    (Color::Yellow, (205, 205, 0)),
+    (Color::Blue, (0, 0, 238)),
+    (Color::Magenta, (205, 0, 205)),
+    (Color::Cyan, (0, 205, 205)),
+    (Color::Gray, (229, 229, 229)),
+    (Color::DarkGray, (127, 127, 127)),
+    (Color::LightRed, (255, 0, 0)),
+    (Color::LightGreen, (0, 255, 0)),
+    (Color::LightYellow, (255, 255, 0)),
+    (Color::LightBlue, (92, 92, 255)),
+    (Color::LightMagenta, (255, 0, 255)),
+    (Color::LightCyan, (0, 255, 255)),
+    (Color::White, (255, 255, 255)),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum ColorDepth {
+    None,
+    Ansi16,
+    Ansi256,
+    TrueColor,
+}
+
+/// What the client terminal can display, as reported by its `TERM`,
+/// `COLORTERM` and locale variables.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Capabilities {
+    pub color: ColorDepth,
+    pub unicode: bool,
+    // What the terminal supports, unless the locale says otherwise
+    term_unicode: bool,
+    // Whether each locale variable sent names UTF-8, in order of precedence
+    lc_all: Option<bool>,
+    lc_ctype: Option<bool>,
+    lang: Option<bool>,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            color: ColorDepth::TrueColor,
+            unicode: true,
+            term_unicode: true,
+            lc_all: None,
+            lc_ctype: None,
+            lang: None,
+        }
+    }
+}
+
+impl Capabilities {
+    pub fn from_term(term: &str) -> Self {
+        let term = term.to_ascii_lowercase();
+        let color = if term.is_empty() || term == "dumb" {
+            ColorDepth::None
+        } else if term.contains("truecolor") || term.contains("24bit") || term.contains("direct") {
+            ColorDepth::TrueColor
+        } else if term.contains("256color") {
+            ColorDepth::Ansi256
+        } else {
+            ColorDepth::Ansi16
+        };
+        let unicode = !(ASCII_TERMS.contains(&term.as_str()) || term.starts_with("vt"));
+        Self {
+            color,
+            unicode,
+            term_unicode: unicode,
+            ..Default::default()
+        }
+    }
+
+    /// Refines the capabilities with an environment variable sent by the client.
+    pub fn apply_env(&mut self, name: &str, value: &str) {
+        match name {
+            "COLORTERM" if matches!(value, "truecolor" | "24bit") => {
+                self.color = ColorDepth::TrueColor;
+            }
+            "LC_ALL" | "LC_CTYPE" | "LANG" => {
+                let value = value.to_ascii_lowercase();
+                // an empty variable counts as unset
+                let utf8 =
+                    (!value.is_empty()).then(|| value.contains("utf-8") || value.contains("utf8"));
+                match name {
+                    "LC_ALL" => self.lc_all = utf8,
+                    "LC_CTYPE" => self.lc_ctype = utf8,
+                    _ => self.lang = utf8,
+                }
+                // the variables may arrive in any order, the first set wins as in POSIX
+                self.unicode = self
+                    .lc_all
+                    .or(self.lc_ctype)
+                    .or(self.lang)
+                    .unwrap_or(self.term_unicode);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn border_set(&self) -> border::Set {
+        if self.unicode {
+            border::ROUNDED
+        } else {
+            ASCII_BORDER
+        }
+    }
+
+    /// The marker in front of the quoted parent of a reply.
+    pub fn quote_marker(&self) -> &'static str {
+        if self.unicode { "╭" } else { ">" }
+    }
+
+    /// Marks text that was cut short.
+    pub fn ellipsis(&self) -> &'static str {
+        if self.unicode { "…" } else { "..." }
+    }
+
+    /// Converts every colour in the text to one the terminal can display.
+    pub fn adapt(&self, text: &mut Text) {
+        text.style = self.adapt_style(text.style);
+        for line in text.lines.iter_mut() {
+            line.style = self.adapt_style(line.style);
+            for span in line.spans.iter_mut() {
+                span.style = self.adapt_style(span.style);
+            }
+        }
+    }
+
+    fn adapt_style(&self, mut style: Style) -> Style {
+        style.fg = style.fg.and_then(|color| self.adapt_color(color));
+        style.bg = style.bg.and_then(|color| self.adapt_color(color));
+        style
+    }
+
+    fn adapt_color(&self, color: Color) -> Option<Color> {
+        match (self.color, color) {
+            (ColorDepth::None, _) => None,
+            (ColorDepth::TrueColor, color) => Some(color),
+            (ColorDepth::Ansi256, Color::Rgb(r, g, b)) => Some(Color::Indexed(to_256((r, g, b)))),
+            (ColorDepth::Ansi16, Color::Rgb(r, g, b)) => Some(to_16((r, g, b))),
+            (ColorDepth::Ansi16, Color::Indexed(index)) => Some(to_16(indexed_to_rgb(index))),
+            (_, color) => Some(color),
+        }
+    }
+}
+
+/// Maps a colour to the 6x6x6 colour cube of 256 colour terminals.
+fn to_256((r, g, b): (u8, u8, u8)) -> u8 {
+    let level = |c: u8| ((c as u16 * 5 + 127) / 255) as u8;
+    16 + 36 * level(r) + 6 * level(g) + level(b)
+}
+
+fn indexed_to_rgb(index: u8) -> (u8, u8, u8) {
+    match index {
+        0..16 => ANSI_COLORS[index as usize].1,
+        16..232 => {
+            let index = index - 16;
+            let level = |c: u8| if c == 0 { 0 } else { 55 + c * 40 };
+            (level(index / 36), level((index / 6) % 6), level(index % 6))
+        }
+        _ => {
+            let gray = 8 + (index - 232) * 10;
+            (gray, gray, gray)
+        }
+    }
+}
+
+/// Finds the closest of the 16 ANSI colours.
+fn to_16((r, g, b): (u8, u8, u8)) -> Color {
+    let distance = |(cr, cg, cb): (u8, u8, u8)| {
+        let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2);
+        d(r, cr) + d(g, cg) + d(b, cb)
+    };
+    ANSI_COLORS
+        .iter()
+        .min_by_key(|(_, rgb)| distance(*rgb))
+        .map(|(color, _)| *color)
+        .unwrap_or(Color::Reset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_term() {
+        let xterm = Capabilities::from_term("xterm-256color");
+        assert_eq!(xterm.color, ColorDepth::Ansi256);
+        assert!(xterm.unicode);
+
+        let vt100 = Capabilities::from_term("vt100");
+        assert_eq!(vt100.color, ColorDepth::Ansi16);
+        assert!(!vt100.unicode);
+
+        assert_eq!(Capabilities::from_term("dumb").color, ColorDepth::None);
+    }
+
+    #[test]
+    fn test_apply_env() {
+        let mut capabilities = Capabilities::from_term("xterm");
+        capabilities.apply_env("COLORTERM", "truecolor");
+        capabilities.apply_env("LANG", "C");
+        assert_eq!(capabilities.color, ColorDepth::TrueColor);
+        assert!(!capabilities.unicode);
+
+        capabilities.apply_env("LC_ALL", "en_US.UTF-8");
+        assert!(capabilities.unicode);
+    }
+
+    #[test]
+    fn test_locale_precedence() {
+        let mut capabilities = Capabilities::from_term("xterm");
+        capabilities.apply_env("LC_ALL", "C");
+        capabilities.apply_env("LANG", "en_US.UTF-8");
+        assert!(!capabilities.unicode);
+
+        capabilities.apply_env("LC_ALL", "");
+        capabilities.apply_env("LC_CTYPE", "POSIX");
+        assert!(!capabilities.unicode);
+        capabilities.apply_env("LC_CTYPE", "");
+        assert!(capabilities.unicode);
+    }
+
+    #[test]
+    fn test_adapt_color() {
+        let orange = Color::Rgb(255, 135, 0);
+        let ansi256 = Capabilities::from_term("xterm-256color");
+        assert_eq!(ansi256.adapt_color(orange), Some(Color::Indexed(214)));
+        let ansi16 = Capabilities::from_term("xterm");
+        assert_eq!(ansi16.adapt_color(orange), Some(Color::Yellow));
+        assert_eq!(
+            ansi16.adapt_color(Color::Indexed(196)),
+            Some(Color::LightRed)
+        );
+        let dumb = Capabilities::from_term("dumb");
+        assert_eq!(dumb.adapt_color(Color::Green), None);
+    }
+}
@@ -0,0 +1,707 @@
+use crate::Error;
+use crate::entity::{self, Entity, Role};
+use crate::history::MessageTarget;
+use crate::invite;
+use crate::lookup::EntityLookup;
+use crate::reaction::Emoji;
+use russh::keys::PublicKey;
+use std::net::IpAddr;
+use std::time::Duration;
+
+pub enum Command {
+    Add(Entity),
+    Rename {
+        from: String,
+        to: String,
+    },
+    Commit,
+    Info(EntityLookup),
+    Ban(EntityLookup),
+    Reload,
+    Edit {
+        target: MessageTarget,
+        text: String,
+    },
+    Delete(MessageTarget),
+    Reply {
+        target: MessageTarget,
+        text: String,
+    },
+    Thread(Option<MessageTarget>),
+    React {
+        target: MessageTarget,
+        emoji: Emoji,
+    },
+    Help(Option<String>),
+    Set(Option<(String, String)>),
+    Audit(usize),
+    Invite {
+        role: Role,
+        uses: u32,
+        expires_in: Duration,
+    },
+    Pending,
+    Approve {
+        fingerprint: String,
+        name: Option<String>,
+    },
+    Deny(String),
+    AddKey(PublicKey),
+    RemoveKey(String),
+    Nick(String),
+    Blocked,
+    Unblock(IpAddr),
+    Lock {
+        lookup: EntityLookup,
+        locked: bool,
+    },
+    Shutdown {
+        delay: Option<Duration>,
+        reason: Option<String>,
+    },
+}
+
+/// Parses the arguments of a command, which are the words following its name,
+/// along with the full text for commands taking free-form text.
+/// Returns `Ok(None)` when the arguments do not fit the command's usage.
+type ArgumentParser = fn(args: &[&str], text: &str) -> Result<Option<Command>, Error>;
+
+pub struct Spec {
+    pub name: &'static str,
+    pub args: &'static str,
+    /// The least privileged role allowed to run the command
+    pub role: Role,
+    pub help: &'static str,
+    parse: ArgumentParser,
+}
+
+impl Spec {
+    pub fn usage(&self) -> String {
+        if self.args.is_empty() {
+            return self.name.to_string();
+        }
+        format!("{} {}", self.name, self.args)
+    }
+
+    pub fn allowed(&self, role: Role) -> bool {
+        self.role == Role::Normal || role == Role::Admin
+    }
+}
+
+pub static REGISTRY: [Spec; 26] = [
+    Spec {
+        name: "/help",
+        args: "[command]",
+        role: Role::Normal,
+        help: "list the commands available to you or describe one of them",
+        parse: |args, _| {
+            Ok(match args {
+                [] => Some(Command::Help(None)),
+                [command] => Some(Command::Help(Some(command.to_string()))),
+                _ => None,
+            })
+        },
+    },
+    Spec {
+        name: "/info",
+        args: "<name|fingerprint>",
+        role: Role::Normal,
+        help: "show the name, role and fingerprint of a member",
+        parse: |args, _| {
+            Ok(match args {
+                [lookup] => Some(Command::Info(lookup.parse()?)),
+                _ => None,
+            })
+        },
+    },
+    Spec {
+        name: "/edit",
+        args: "<id|last> <text>",
+        role: Role::Normal,
+        help: "replace the text of a message you sent",
+        parse: |args, text| {
+            Ok(match args {
+                [target, _, ..] => Some(Command::Edit {
+                    target: target.parse()?,
+                    text: remainder(text, 2).to_string(),
+                }),
+                _ => None,
+            })
+        },
+    },
+    Spec {
+        name: "/delete",
+        args: "<id|last>",
+        role: Role::Normal,
+        help: "delete a message you sent, admins may delete any message",
+        parse: |args, _| {
+            Ok(match args {
+                [target] => Some(Command::Delete(target.parse()?)),
+                _ => None,
+            })
+        },
+    },
+    Spec {
+        name: "/reply",
+        args: "<id|last> <text>",
+        role: Role::Normal,
+        help: "reply to a message, quoting it above your own",
+        parse: |args, text| {
+            Ok(match args {
+                [target, _, ..] => Some(Command::Reply {
+                    target: target.parse()?,
+                    text: remainder(text, 2).to_string(),
+                }),
+                _ => None,
+            })
+        },
+    },
+    Spec {
+        name: "/thread",
+        args: "[id|last]",
+        role: Role::Normal,
+        help: "show only the thread containing a message, or the full history without an id",
+        parse: |args, _| {
+            Ok(match args {
+                [] => Some(Command::Thread(None)),
+                [target] => Some(Command::Thread(Some(target.parse()?))),
+                _ => None,
+            })
+        },
+    },
+    Spec {
+        name: "/react",
+        args: "<id|last> <emoji|:shortcode:>",
+        role: Role::Normal,
+        help: "toggle an emoji reaction on a message, last being the latest message",
+        parse: |args, _| {
+            Ok(match args {
+                [target, emoji] => Some(Command::React {
+                    target: target.parse()?,
+                    emoji: emoji.parse()?,
+                }),
+                _ => None,
+            })
+        },
+    },
+    Spec {
+        name: "/set",
+        args: "[theme|timestamps|compact] [value]",
+        role: Role::Normal,
+        help: "change a display preference or show the current ones",
+        parse: |args, _| {
+            Ok(match args {
+                [] => Some(Command::Set(None)),
+                [key, value] => Some(Command::Set(Some((key.to_string(), value.to_string())))),
+                _ => None,
+            })
+        },
+    },
+    Spec {
+        name: "/nick",
+        args: "<name>",
+        role: Role::Normal,
+        help: "change your own name",
+        parse: |args, _| {
+            Ok(match args {
+                [name] => Some(Command::Nick(name.to_string())),
+                _ => None,
+            })
+        },
+    },
+    Spec {
+        name: "/addkey",
+        args: "<key line>",
+        role: Role::Normal,
+        help: "add another public key of yours to join with under the same name",
+        parse: |args, _| {
+            if args.is_empty() {
+                return Ok(None);
+            }
+            let key = PublicKey::from_openssh(&args.join(" ")).map_err(entity::Error::from)?;
+            Ok(Some(Command::AddKey(key)))
+        },
+    },
+    Spec {
+        name: "/removekey",
+        args: "<fingerprint>",
+        role: Role::Normal,
+        help: "remove one of your public keys other than the one you joined with",
+        parse: |args, _| {
+            Ok(match args {
+                [fingerprint] => Some(Command::RemoveKey(fingerprint.to_string())),
+                _ => None,
+            })
+        },
+    },
+    Spec {
+        name: "/add",
+        args: "<key line> [--name <name>] [--role admin|normal]",
+        role: Role::Admin,
+        help: "add an OpenSSH public key to the in-memory keychain, named after its comment by default",
+        parse: parse_add,
+    },
+    Spec {
+        name: "/invite",
+        args: "[--role admin|normal] [--uses <count>] [--expires <duration>]",
+        role: Role::Admin,
+        help: "create an invite token for newcomers to join with, 1 use within 24h by default",
+        parse: parse_invite,
+    },
+    Spec {
+        name: "/pending",
+        args: "",
+        role: Role::Admin,
+        help: "list the keys waiting in the lobby for approval",
+        parse: |args, _| Ok(args.is_empty().then_some(Command::Pending)),
+    },
+    Spec {
+        name: "/approve",
+        args: "<fingerprint> [name]",
+        role: Role::Admin,
+        help: "let a waiting key in, the name being optional for invited keys",
+        parse: |args, _| {
+            Ok(match args {
+                [fingerprint] => Some(Command::Approve {
+                    fingerprint: fingerprint.to_string(),
+                    name: None,
+                }),
+                [fingerprint, name] => Some(Command::Approve {
+                    fingerprint: fingerprint.to_string(),
+                    name: Some(name.to_string()),
+                }),
+                _ => None,
+            })
+        },
+    },
+    Spec {
+        name: "/deny",
+        args: "<fingerprint>",
+        role: Role::Admin,
+        help: "turn away a waiting key and disconnect it",
+        parse: |args, _| {
+            Ok(match args {
+                [fingerprint] => Some(Command::Deny(fingerprint.to_string())),
+                _ => None,
+            })
+        },
+    },
+    Spec {
+        name: "/ban",
+        args: "<name|fingerprint>",
+        role: Role::Admin,
+        help: "remove a member from the keychain and disconnect them",
+        parse: |args, _| {
+            Ok(match args {
+                [lookup] => Some(Command::Ban(lookup.parse()?)),
+                _ => None,
+            })
+        },
+    },
+    Spec {
+        name: "/rename",
+        args: "<from> <to>",
+        role: Role::Admin,
+        help: "change the name of a member",
+        parse: |args, _| {
+            Ok(match args {
+                [from, to] => Some(Command::Rename {
+                    from: from.to_string(),
+                    to: to.to_string(),
+                }),
+                _ => None,
+            })
+        },
+    },
+    Spec {
+        name: "/lock",
+        args: "<name|fingerprint>",
+        role: Role::Admin,
+        help: "stop a member from changing their name with /nick",
+        parse: |args, _| {
+            Ok(match args {
+                [lookup] => Some(Command::Lock {
+                    lookup: lookup.parse()?,
+                    locked: true,
+                }),
+                _ => None,
+            })
+        },
+    },
+    Spec {
+        name: "/unlock",
+        args: "<name|fingerprint>",
+        role: Role::Admin,
+        help: "let a member change their name with /nick again",
+        parse: |args, _| {
+            Ok(match args {
+                [lookup] => Some(Command::Lock {
+                    lookup: lookup.parse()?,
+                    locked: false,
+                }),
+                _ => None,
+            })
+        },
+    },
+    Spec {
+        name: "/blocked",
+        args: "",
+        role: Role::Admin,
+        help: "list the addresses locked out after failing to authenticate",
+        parse: |args, _| Ok(args.is_empty().then_some(Command::Blocked)),
+    },
+    Spec {
+        name: "/unblock",
+        args: "<address>",
+        role: Role::Admin,
+        help: "let a locked out address authenticate again",
+        parse: |args, _| {
+            Ok(match args {
+                [address] => Some(Command::Unblock(
+                    address
+                        .parse()
+                        .map_err(|_| Error::InvalidAddress(address.to_string()))?,
+                )),
+                _ => None,
+            })
+        },
+    },
+    Spec {
+        name: "/commit",
+        args: "",
+        role: Role::Admin,
+        help: "write the in-memory keychain to the Authfile",
+        parse: |args, _| Ok(args.is_empty().then_some(Command::Commit)),
+    },
+    Spec {
+        name: "/reload",
+        args: "",
+        role: Role::Admin,
+        help: "replace the in-memory keychain with the Authfile, disconnecting removed members",
+        parse: |args, _| Ok(args.is_empty().then_some(Command::Reload)),
+    },
+    Spec {
+        name: "/shutdown",
+        args: "[delay] [reason]",
+        role: Role::Admin,
+        help: "tell everyone the server is stopping, then disconnect them after the delay",
+        parse: parse_shutdown,
+    },
+    Spec {
+        name: "/audit",
+        args: "[count]",
+        role: Role::Admin,
+        help: "show the latest administrative actions from the audit log, 10 by default",
+        parse: |args, _| {
+            Ok(match args {
+                [] => Some(Command::Audit(10)),
+                [count] => count.parse().ok().map(Command::Audit),
+                _ => None,
+            })
+        },
+    },
+];
+
+/// Finds a command by name, with or without the leading slash.
+pub fn lookup(name: &str) -> Option<&'static Spec> {
+    let name = name.trim_start_matches('/');
+    REGISTRY
+        .iter()
+        .find(|spec| spec.name.trim_start_matches('/') == name)
+}
+
+/// Names of the commands available to the role.
+pub fn names(role: Role) -> impl Iterator<Item = &'static str> {
+    REGISTRY
+        .iter()
+        .filter(move |spec| spec.allowed(role))
+        .map(|spec| spec.name)
+}
+
+impl Command {
+    /// The action and its target recorded in the audit log, for administrative
+    /// commands and changes to the keys of a member.
+    pub fn audit_entry(&self) -> Option<(&'static str, String)> {
+        match self {
+            Command::Add(entity) => Some(("add", entity.fingerprint())),
+            Command::Ban(lookup) => Some(("ban", lookup.to_string())),
+            Command::Rename { from, to } => Some(("rename", format!("{from} -> {to}"))),
+            Command::Commit => Some(("commit", "authfile".to_string())),
+            Command::Reload => Some(("reload", "authfile".to_string())),
+            Command::Approve { fingerprint, name } => Some((
+                "approve",
+                match name {
+                    Some(name) => format!("{fingerprint} as {name}"),
+                    None => fingerprint.clone(),
+                },
+            )),
+            Command::Deny(fingerprint) => Some(("deny", fingerprint.clone())),
+            // members manage their own keys, which is recorded all the same
+            Command::AddKey(key) => Some((
+                "addkey",
+                key.fingerprint(russh::keys::HashAlg::Sha256).to_string(),
+            )),
+            Command::RemoveKey(fingerprint) => Some(("removekey", fingerprint.clone())),
+            Command::Nick(name) => Some(("nick", name.clone())),
+            Command::Unblock(address) => Some(("unblock", address.to_string())),
+            Command::Shutdown { delay, reason } => Some((
+                "shutdown",
+                format!(
+                    "in {}s: {}",
+                    delay.unwrap_or_default().as_secs(),
+                    reason.as_deref().unwrap_or("no reason given")
+                ),
+            )),
+            Command::Lock {
+                lookup,
+                locked: true,
+            } => Some(("lock", lookup.to_string())),
+            Command::Lock {
+                lookup,
+                locked: false,
+            } => Some(("unlock", lookup.to_string())),
+            // the token itself is a secret and stays out of the log
+            Command::Invite {
+                role,
+                uses,
+                expires_in,
+            } => Some((
+                "invite",
+                format!("{role}, {uses} uses, {}s", expires_in.as_secs()),
+            )),
+            _ => None,
+        }
+    }
+
+    /// Parses the text into a command. Text that does not start with
+    /// the name of a command is a plain message, for which `Ok(None)` is returned.
+    pub fn parse(text: &str, role: Role, name: String) -> Result<Option<Self>, Error> {
+        let mut words = text.split_whitespace();
+        let Some(spec) = words
+            .next()
+            .filter(|word| word.starts_with('/'))
+            .and_then(lookup)
+        else {
+            return Ok(None);
+        };
+        if !spec.allowed(role) {
+            return Err(Error::NotAnAdmin(name));
+        }
+
+        let args: Vec<&str> = words.collect();
+        match (spec.parse)(&args, text)? {
+            Some(command) => Ok(Some(command)),
+            None => Err(Error::CommandUsage(spec.usage())),
+        }
+    }
+}
+
+/// Builds the `/help` dossier, listing only the commands available to the role.
+pub fn help(role: Role, command: Option<&str>) -> Result<String, Error> {
+    let Some(command) = command else {
+        let mut contents = String::from("\ncommands:\n");
+        for spec in REGISTRY.iter().filter(|spec| spec.allowed(role)) {
+            contents.push_str(&format!("  {:<40} {}\n", spec.usage(), spec.help));
+        }
+        return Ok(contents);
+    };
+
+    let spec = lookup(command)
+        .filter(|spec| spec.allowed(role))
+        .ok_or_else(|| Error::UnknownCommand(command.to_string()))?;
+    Ok(format!(
+        "\nusage: {}\nrequires: {} privileges\n{}\n",
+        spec.usage(),
+        spec.role,
+        spec.help
+    ))
+}
+
+/// Parses `/add` arguments, which are the words of a public key line
+/// with `--name` and `--role` options anywhere among them.
+fn parse_add(args: &[&str], _: &str) -> Result<Option<Command>, Error> {
+    let mut key_line = vec![];
+    let mut name = None;
+    let mut role = None;
+    let mut args = args.iter();
+    while let Some(&arg) = args.next() {
+        match arg {
+            "--name" => match args.next() {
+                Some(&value) => name = Some(value),
+                None => return Ok(None),
+            },
+            "--role" => match args.next() {
+                Some(value) => role = Some(value.parse()?),
+                None => return Ok(None),
+            },
+            word => key_line.push(word),
+        }
+    }
+    if key_line.is_empty() {
+        return Ok(None);
+    }
+    let entity = Entity::from_key_line(&key_line.join(" "), name, role)?;
+    Ok(Some(Command::Add(entity)))
+}
+
+/// Parses the `/invite` options, each of which has a default.
+fn parse_invite(args: &[&str], _: &str) -> Result<Option<Command>, Error> {
+    let mut role = Role::Normal;
+    let mut uses = 1;
+    let mut expires_in = Duration::from_secs(24 * 60 * 60);
+    for option in args.chunks(2) {
+        match option {
+            ["--role", value] => role = value.parse()?,
+            ["--uses", value] => match value.parse() {
+                Ok(count) if count > 0 => uses = count,
+                _ => return Ok(None),
+            },
+            ["--expires", value] => expires_in = invite::parse_duration(value)?,
+            _ => return Ok(None),
+        }
+    }
+    Ok(Some(Command::Invite {
+        role,
+        uses,
+        expires_in,
+    }))
+}
+
+/// Parses the `/shutdown` arguments, an optional delay followed by a free-form reason.
+fn parse_shutdown(args: &[&str], text: &str) -> Result<Option<Command>, Error> {
+    let delay = args
+        .first()
+        .and_then(|delay| invite::parse_duration(delay).ok());
+    let skip = if delay.is_some() { 2 } else { 1 };
+    let reason = Some(remainder(text, skip))
+        .filter(|reason| !reason.is_empty())
+        .map(str::to_string);
+    Ok(Some(Command::Shutdown { delay, reason }))
+}
+
+/// Returns the text following the first `skip` whitespace separated words.
+fn remainder(text: &str, skip: usize) -> &str {
+    let mut rest = text;
+    for _ in 0..skip {
+        rest = rest.trim_start();
+        rest = rest.find(char::is_whitespace).map_or("", |i| &rest[i..]);
+    }
+    rest.trim_start()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str, role: Role) -> Result<Option<Command>, Error> {
+        Command::parse(text, role, "h@cafe".to_string())
+    }
+
+    #[test]
+    fn test_plain_messages() {
+        for text in ["hello", "/shrug", "a /commit", ""] {
+            assert!(matches!(parse(text, Role::Normal), Ok(None)), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn test_permissions_and_usage() {
+        assert!(matches!(
+            parse("/commit", Role::Normal),
+            Err(Error::NotAnAdmin(_))
+        ));
+        assert!(matches!(
+            parse("/commit", Role::Admin),
+            Ok(Some(Command::Commit))
+        ));
+        match parse("/rename bob", Role::Admin) {
+            Err(Error::CommandUsage(usage)) => assert_eq!(usage, "/rename <from> <to>"),
+            _ => panic!("expected a usage error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_add_key_line() {
+        let key =
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIA/wbGoIUsbBHFbnXj2g+23C8sUgYkZTq0TrBm0MMWnx";
+
+        let Ok(Some(Command::Add(entity))) = parse(&format!("/add {key} bob@work"), Role::Admin)
+        else {
+            panic!("failed to parse /add with a key line");
+        };
+        assert_eq!(entity.name().await, "bob@work");
+        assert_eq!(entity.role().await, Role::Normal);
+
+        let text = format!("/add --role admin {key} bob@work --name dri");
+        let Ok(Some(Command::Add(entity))) = parse(&text, Role::Admin) else {
+            panic!("failed to parse /add with options");
+        };
+        assert_eq!(entity.name().await, "dri");
+        assert_eq!(entity.role().await, Role::Admin);
+
+        assert!(matches!(
+            parse(&format!("/add {key} --role"), Role::Admin),
+            Err(Error::CommandUsage(_))
+        ));
+        assert!(matches!(
+            parse(&format!("/add {key} --role owner"), Role::Admin),
+            Err(Error::EntityParsing(_))
+        ));
+    }
+
+    #[test]
+    fn test_invite_options() {
+        let Ok(Some(Command::Invite {
+            role,
+            uses,
+            expires_in,
+        })) = parse("/invite --expires 2h --uses 3", Role::Admin)
+        else {
+            panic!("failed to parse /invite");
+        };
+        assert_eq!(role, Role::Normal);
+        assert_eq!(uses, 3);
+        assert_eq!(expires_in, Duration::from_secs(7200));
+
+        for usage in ["/invite --uses 0", "/invite --uses", "/invite admin"] {
+            assert!(
+                matches!(parse(usage, Role::Admin), Err(Error::CommandUsage(_))),
+                "{usage:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_free_form_text() {
+        let Ok(Some(Command::Edit { text, .. })) = parse("/edit 3  fixed   it\nok", Role::Normal)
+        else {
+            panic!("failed to parse /edit");
+        };
+        assert_eq!(text, "fixed   it\nok");
+    }
+
+    #[test]
+    fn test_help_filtered_by_role() {
+        let normal = help(Role::Normal, None).unwrap();
+        assert!(normal.contains("/edit") && !normal.contains("/ban"));
+        assert!(help(Role::Admin, None).unwrap().contains("/ban"));
+        assert!(help(Role::Normal, Some("ban")).is_err());
+        assert!(help(Role::Admin, Some("/ban")).is_ok());
+    }
+
+    #[test]
+    fn test_shutdown_arguments() {
+        for (text, expected_delay, expected_reason) in [
+            ("/shutdown", None, None),
+            ("/shutdown 5m", Some(300), None),
+            ("/shutdown 5m back  soon", Some(300), Some("back  soon")),
+            ("/shutdown upgrading now", None, Some("upgrading now")),
+        ] {
+            let Ok(Some(Command::Shutdown { delay, reason })) = parse(text, Role::Admin) else {
+                panic!("failed to parse {text:?}");
+            };
+            assert_eq!(delay.map(|delay| delay.as_secs()), expected_delay);
+            assert_eq!(reason.as_deref(), expected_reason);
+        }
+    }
+}
@@ -1,12 +1,15 @@
 use std::fmt::Display;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::SystemTime;
 use thiserror::Error;
 use tokio::sync::RwLock;
 
 use russh::keys::PublicKey;
 use russh::keys::ssh_key::public::KeyData;
-#[derive(Clone, Debug, PartialEq, Copy)]
+
+use crate::sanitize;
+#[derive(Clone, Debug, PartialEq, Copy, serde::Serialize, serde::Deserialize)]
 pub enum Role {
     Admin,
     Normal,
@@ -22,10 +25,26 @@ impl Display for Role {
     }
 }
 
+impl FromStr for Role {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "admin" => Ok(Role::Admin),
+            "normal" => Ok(Role::Normal),
+            _ => Err(Error::UnknownRole(s.to_string())),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Persona {
     name: String,
     role: Role,
+    // When the member last changed their own name with /nick
+    nicked_at: Option<SystemTime>,
+    // Whether an admin has locked the name against /nick
+    locked: bool,
 }
 
 impl Persona {
@@ -40,6 +59,14 @@ impl Persona {
     pub fn role(&self) -> Role {
         self.role
     }
+
+    pub fn nicked_at(&self) -> Option<SystemTime> {
+        self.nicked_at
+    }
+
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
 }
 
 pub type ArcPersona = Arc<RwLock<Persona>>;
@@ -61,7 +88,28 @@ impl Entity {
 
     /// NOTE: interior mutation on persona
     pub async fn set_name(&self, name: &str) {
-        self.persona.write().await.name = sanitize_name(name);
+        self.persona.write().await.name = sanitize::name(name);
+    }
+
+    /// The member changes their own name, which starts the cooldown.
+    /// NOTE: interior mutation on persona
+    pub async fn nick(&self, name: &str) {
+        let mut persona = self.persona.write().await;
+        persona.name = sanitize::name(name);
+        persona.nicked_at = Some(SystemTime::now());
+    }
+
+    /// NOTE: interior mutation on persona
+    pub async fn set_locked(&self, locked: bool) {
+        self.persona.write().await.locked = locked;
+    }
+
+    /// Brings back what the Authfile does not record after a restart.
+    /// NOTE: interior mutation on persona
+    pub async fn restore(&self, nicked_at: Option<SystemTime>, locked: bool) {
+        let mut persona = self.persona.write().await;
+        persona.nicked_at = nicked_at;
+        persona.locked = locked;
     }
 
     pub async fn to_pubkey(&self) -> PublicKey {
@@ -101,41 +149,66 @@ impl Entity {
     pub fn persona(&self) -> Arc<RwLock<Persona>> {
         self.persona.clone()
     }
-}
 
-fn sanitize_name(s: &str) -> String {
-    let mut sanitized = String::with_capacity(s.len());
-    for c in s.chars() {
-        let ok = c.is_ascii_alphanumeric() || "@_-.".contains(c);
-        if !ok {
-            continue;
-        }
-        sanitized.push(c);
+    /// Adds another key to the identity of a member, sharing their name and role.
+    pub fn with_persona(key: PublicKey, persona: ArcPersona) -> Self {
+        Entity { persona, key }
     }
-    sanitized
-}
 
-impl FromStr for Entity {
-    type Err = Error;
+    /// Makes the key part of the other entity's identity.
+    pub fn link(&mut self, other: &Entity) {
+        self.persona = other.persona.clone();
+    }
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let key = PublicKey::from_openssh(s)?;
+    /// Whether both keys belong to the same member.
+    pub fn same_identity(&self, other: &Entity) -> bool {
+        Arc::ptr_eq(&self.persona, &other.persona)
+    }
+
+    /// Parses an OpenSSH public key line, with the name and role given
+    /// taking precedence over those in the key comment.
+    pub fn from_key_line(
+        line: &str,
+        name: Option<&str>,
+        role: Option<Role>,
+    ) -> Result<Self, Error> {
+        let key = PublicKey::from_openssh(line)?;
 
         let comment = key.comment();
-        let (name, role) = match comment.rsplit_once(":") {
+        let (comment_name, comment_role) = match comment.rsplit_once(":") {
             Some((name, "admin")) => (name, Role::Admin),
             None => (comment, Role::Normal),
+            // an explicit role overrides whatever the comment says
+            Some((name, _)) if role.is_some() => (name, Role::Normal),
             _ => {
                 return Err(Error::InvalidRole(comment.to_string()));
             }
         };
 
+        Ok(Self::new(
+            key.clone(),
+            name.unwrap_or(comment_name),
+            role.unwrap_or(comment_role),
+        ))
+    }
+
+    pub fn new(key: PublicKey, name: &str, role: Role) -> Self {
         let persona = Persona {
-            name: sanitize_name(name),
+            name: sanitize::name(name),
             role,
+            nicked_at: None,
+            locked: false,
         };
         let persona = Arc::new(RwLock::new(persona));
-        Ok(Entity { persona, key })
+        Entity { persona, key }
+    }
+}
+
+impl FromStr for Entity {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_key_line(s, None, None)
     }
 }
 
@@ -145,4 +218,6 @@ pub enum Error {
     PublicKeyParsing(#[from] russh::keys::ssh_key::Error),
     #[error("invalid role specified in authorization file at line: {0}")]
     InvalidRole(String),
+    #[error("unknown role {0:?}, expected admin or normal")]
+    UnknownRole(String),
 }
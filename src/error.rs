@@ -1,3 +1,4 @@
+use crate::audit;
 use crate::authfile;
 use crate::entity;
 
@@ -11,8 +12,10 @@ pub enum Error {
     Authfile(#[from] authfile::Error),
     #[error("failed to resize frame as requested by client {id}")]
     FrameResize { source: std::io::Error, id: usize },
-    #[error("failed to parse command {0:#?}")]
-    CommandParse(String),
+    #[error("usage: {0}")]
+    CommandUsage(String),
+    #[error("no such command {0:?}, send /help to list commands")]
+    UnknownCommand(String),
     #[error("unable to spawn a terminal for client {id}")]
     TerminalSessionSpawn { source: std::io::Error, id: usize },
     #[error("failed to parse entity lookup: {0}")]
@@ -23,4 +26,82 @@ pub enum Error {
     EntityParsing(#[from] entity::Error),
     #[error("users cannot ban themselves")]
     NoBanSelf,
+    #[error("failed to parse message id {0:?}: expected a number or \"last\"")]
+    MessageTarget(String),
+    #[error("no message with id {0} in history")]
+    MessageNotFound(u64),
+    #[error("message {0} was not sent by you")]
+    NotMessageAuthor(u64),
+    #[error("you have no previous message in history")]
+    NoPreviousMessage,
+    #[error("{0:?} is not an emoji or a known shortcode")]
+    InvalidReaction(String),
+    #[error("no such preference {0:?}, expected theme, timestamps or compact")]
+    UnknownPreference(String),
+    #[error("invalid value {value:?} for {key}, expected on or off")]
+    InvalidPreferenceValue { key: String, value: String },
+    #[error("no such theme {0:?}, send /set to list themes")]
+    UnknownTheme(String),
+    #[error("failed to access the audit log")]
+    Audit(#[from] audit::Error),
+    #[error("failed to save preferences")]
+    PreferencesNotSaved(#[source] std::io::Error),
+    #[error("no member matches {0:?}")]
+    NoSuchMember(String),
+    #[error("{lookup:?} matches several members, use one of the fingerprints {candidates}")]
+    AmbiguousMember { lookup: String, candidates: String },
+    #[error("the key has no comment to name the member after, pass --name")]
+    UnnamedMember,
+    #[error("the key {0} is already in the keychain")]
+    KeyAlreadyAdded(String),
+    #[error("the name {0:?} is already taken")]
+    NameTaken(String),
+    #[error("invalid duration {0:?}, expected a number followed by s, m, h or d")]
+    InvalidDuration(String),
+    #[error("the lobby is full")]
+    LobbyFull,
+    #[error("no key with fingerprint {0} is waiting in the lobby")]
+    NotPending(String),
+    #[error("a name is needed to approve {0}")]
+    ApprovalNeedsName(String),
+    #[error("none of your keys has the fingerprint {0}")]
+    NotYourKey(String),
+    #[error("you cannot remove the key you joined with")]
+    NoRemoveCurrentKey,
+    #[error("{0:?} has none of the letters, digits or @_-. allowed in names")]
+    InvalidName(String),
+    #[error("you may change your name again in {0}s")]
+    NickCooldown(u64),
+    #[error("your name has been locked by an admin")]
+    NameLocked,
+    #[error("{0:?} is not an IP address")]
+    InvalidAddress(String),
+    #[error("{0} is not blocked")]
+    NotBlocked(std::net::IpAddr),
+    #[error("the server is already shutting down")]
+    AlreadyShuttingDown,
+    #[error("the server is restarting, send it again in a moment")]
+    Restarting,
+    #[error("failed to write the keychain to {path}")]
+    KeychainNotWritten {
+        source: std::io::Error,
+        path: String,
+    },
+    #[error("failed to replace {path} with the committed keychain")]
+    AuthfileNotReplaced {
+        source: std::io::Error,
+        path: String,
+    },
+}
+
+/// Describes the error along with its sources, for showing to members
+/// who have no access to the server logs.
+pub fn describe(error: &dyn std::error::Error) -> String {
+    let mut description = error.to_string();
+    let mut source = error.source();
+    while let Some(error) = source {
+        description.push_str(&format!(": {error}"));
+        source = error.source();
+    }
+    description
 }
@@ -0,0 +1,247 @@
+use crate::Error;
+use crate::message::Message;
+use ringbuffer::{AllocRingBuffer, RingBuffer};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+pub type MessageId = u64;
+
+#[derive(Clone)]
+pub(crate) struct Entry {
+    pub id: MessageId,
+    pub sent_at: SystemTime,
+    pub message: Message,
+}
+
+impl Entry {
+    /// The time the entry was added as `HH:MM` in UTC.
+    pub fn clock(&self) -> String {
+        let since_epoch = self
+            .sent_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let minutes = since_epoch / 60;
+        format!("{:02}:{:02}", (minutes / 60) % 24, minutes % 60)
+    }
+}
+
+/// The chat history, where each entry is assigned a monotonically increasing ID
+/// that remains stable even after older entries are evicted from the buffer.
+pub(crate) struct History {
+    entries: AllocRingBuffer<Entry>,
+    next_id: MessageId,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: AllocRingBuffer::new(capacity),
+            next_id: 1,
+        }
+    }
+
+    pub fn enqueue(&mut self, message: Message) -> MessageId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.entries.enqueue(Entry {
+            id,
+            sent_at: SystemTime::now(),
+            message,
+        });
+        id
+    }
+
+    /// Rebuilds the history from the entries of a previous server process,
+    /// carrying on with the IDs where it left off.
+    pub fn restore(capacity: usize, entries: Vec<Entry>, next_id: MessageId) -> Self {
+        let mut history = Self::new(capacity);
+        history.entries.extend(entries);
+        history.next_id = next_id;
+        history
+    }
+
+    pub fn next_id(&self) -> MessageId {
+        self.next_id
+    }
+
+    pub fn get_mut(&mut self, id: MessageId) -> Option<&mut Entry> {
+        self.entries.iter_mut().find(|entry| entry.id == id)
+    }
+
+    /// The ID of the most recent chat message by the author with the given fingerprint
+    /// that has not been deleted.
+    pub fn last_by(&self, fingerprint: &str) -> Option<MessageId> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|entry| match &entry.message {
+                Message::Chat(chat) => chat.fingerprint == fingerprint && !chat.deleted,
+                _ => false,
+            })
+            .map(|entry| entry.id)
+    }
+
+    /// The ID of the most recent chat message by anyone that has not been deleted.
+    pub fn last_chat(&self) -> Option<MessageId> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|entry| matches!(&entry.message, Message::Chat(chat) if !chat.deleted))
+            .map(|entry| entry.id)
+    }
+
+    /// Drops reactions from every reactor whose fingerprint does not satisfy the predicate.
+    pub fn retain_reactors<F: Fn(&str) -> bool>(&mut self, keep: F) {
+        for entry in self.entries.iter_mut() {
+            let Message::Chat(chat) = &mut entry.message else {
+                continue;
+            };
+            for reaction in chat.reactions.iter_mut() {
+                reaction.reactors.retain(|r| keep(&r.fingerprint));
+            }
+            chat.reactions.retain(|r| !r.reactors.is_empty());
+        }
+    }
+
+    pub fn resolve(&self, target: &MessageTarget, fingerprint: &str) -> Result<MessageId, Error> {
+        match target {
+            MessageTarget::Id(id) => Ok(*id),
+            MessageTarget::Last => self.last_by(fingerprint).ok_or(Error::NoPreviousMessage),
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<Entry> {
+        self.entries.to_vec()
+    }
+
+    pub fn contains(&self, id: MessageId) -> bool {
+        self.entries.iter().any(|entry| entry.id == id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.entries.capacity()
+    }
+}
+
+/// Maps the ID of every entry to the ID of the message that started its thread.
+/// Entries that are not replies are the roots of their own threads.
+pub(crate) fn thread_roots(entries: &[Entry]) -> HashMap<MessageId, MessageId> {
+    let parents: HashMap<MessageId, MessageId> = entries
+        .iter()
+        .filter_map(|entry| match &entry.message {
+            Message::Chat(chat) => chat.parent.map(|parent| (entry.id, parent)),
+            _ => None,
+        })
+        .collect();
+
+    entries
+        .iter()
+        .map(|entry| {
+            let mut root = entry.id;
+            // parents always have smaller IDs, so this cannot cycle
+            while let Some(parent) = parents.get(&root) {
+                root = *parent;
+            }
+            (entry.id, root)
+        })
+        .collect()
+}
+
+/// Refers to a message in the history either by its ID or as
+/// the last message sent by the issuer of a command.
+pub enum MessageTarget {
+    Id(MessageId),
+    Last,
+}
+
+impl FromStr for MessageTarget {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "last" {
+            return Ok(MessageTarget::Last);
+        }
+        s.trim_start_matches('#')
+            .parse()
+            .map(MessageTarget::Id)
+            .map_err(|_| Error::MessageTarget(s.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Chat;
+
+    fn chat(fingerprint: &str, text: &str) -> Message {
+        Message::Chat(Chat::new(
+            "h@cafe".to_string(),
+            fingerprint.to_string(),
+            text.to_string(),
+        ))
+    }
+
+    #[test]
+    fn test_ids_survive_eviction() {
+        let mut history = History::new(2);
+        for text in ["one", "two", "three"] {
+            history.enqueue(chat("SHA256:a", text));
+        }
+        let ids: Vec<MessageId> = history.to_vec().iter().map(|e| e.id).collect();
+        assert_eq!(ids, [2, 3]);
+        assert!(history.get_mut(1).is_none());
+    }
+
+    #[test]
+    fn test_resolve_last_skips_deleted_and_others() {
+        let mut history = History::new(8);
+        let first = history.enqueue(chat("SHA256:a", "one"));
+        let second = history.enqueue(chat("SHA256:a", "two"));
+        history.enqueue(chat("SHA256:b", "three"));
+
+        if let Some(Entry {
+            message: Message::Chat(chat),
+            ..
+        }) = history.get_mut(second)
+        {
+            chat.deleted = true;
+        }
+
+        let id = history
+            .resolve(&"last".parse().unwrap(), "SHA256:a")
+            .expect("failed to resolve last message");
+        assert_eq!(id, first);
+        assert!("#3".parse::<MessageTarget>().is_ok());
+        assert!("latest".parse::<MessageTarget>().is_err());
+    }
+
+    #[test]
+    fn test_thread_roots() {
+        let mut history = History::new(8);
+        let root = history.enqueue(chat("SHA256:a", "question"));
+        let other = history.enqueue(chat("SHA256:b", "unrelated"));
+        let reply = history.enqueue(Message::Chat(Chat::reply(
+            "bob@work".to_string(),
+            "SHA256:b".to_string(),
+            "answer".to_string(),
+            root,
+        )));
+        let nested = history.enqueue(Message::Chat(Chat::reply(
+            "h@cafe".to_string(),
+            "SHA256:a".to_string(),
+            "thanks".to_string(),
+            reply,
+        )));
+
+        let roots = thread_roots(&history.to_vec());
+        assert_eq!(roots[&reply], root);
+        assert_eq!(roots[&nested], root);
+        assert_eq!(roots[&other], other);
+    }
+}
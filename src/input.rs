@@ -0,0 +1,132 @@
+use std::collections::VecDeque;
+
+// The number of sent messages each client can recall
+const MAX_SENT: usize = 64;
+
+/// Messages previously sent by a client, navigable like a shell history.
+#[derive(Default)]
+pub struct InputHistory {
+    sent: VecDeque<String>,
+    // Index into sent while navigating, None when editing a fresh draft
+    position: Option<usize>,
+    // The draft being edited before navigation began
+    stash: String,
+}
+
+impl InputHistory {
+    pub fn push(&mut self, text: &str) {
+        self.position = None;
+        if text.trim().is_empty() || self.sent.back().is_some_and(|last| last == text) {
+            return;
+        }
+        self.sent.push_back(text.to_string());
+        if self.sent.len() > MAX_SENT {
+            self.sent.pop_front();
+        }
+    }
+
+    /// Steps back to an older message, stashing the current draft on the first step.
+    pub fn older(&mut self, current: &str) -> Option<&str> {
+        let position = match self.position {
+            None if self.sent.is_empty() => return None,
+            None => {
+                self.stash = current.to_string();
+                self.sent.len() - 1
+            }
+            Some(0) => return None,
+            Some(position) => position - 1,
+        };
+        self.position = Some(position);
+        Some(&self.sent[position])
+    }
+
+    /// Steps forward to a newer message, restoring the stashed draft past the newest.
+    pub fn newer(&mut self) -> Option<String> {
+        let position = self.position?;
+        if position + 1 < self.sent.len() {
+            self.position = Some(position + 1);
+            return Some(self.sent[position + 1].clone());
+        }
+        self.position = None;
+        Some(std::mem::take(&mut self.stash))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Completion {
+    None,
+    /// The only candidate matching the word
+    Unique(String),
+    /// The longest prefix shared by all matching candidates
+    Partial {
+        prefix: String,
+        options: Vec<String>,
+    },
+}
+
+/// Completes the word against the candidates.
+pub fn complete<S: AsRef<str>>(word: &str, candidates: &[S]) -> Completion {
+    let mut options: Vec<String> = candidates
+        .iter()
+        .map(|c| c.as_ref())
+        .filter(|c| c.starts_with(word))
+        .map(str::to_string)
+        .collect();
+    options.sort();
+    options.dedup();
+
+    match options.as_slice() {
+        [] => Completion::None,
+        [only] => Completion::Unique(only.clone()),
+        [first, rest @ ..] => {
+            let mut prefix = first.clone();
+            for option in rest {
+                while !option.starts_with(&prefix) {
+                    prefix.pop();
+                }
+            }
+            Completion::Partial { prefix, options }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history_navigation() {
+        let mut history = InputHistory::default();
+        history.push("one");
+        history.push("two");
+        history.push("two");
+
+        assert_eq!(history.older("draft"), Some("two"));
+        assert_eq!(history.older(""), Some("one"));
+        assert_eq!(history.older(""), None);
+        assert_eq!(history.newer().as_deref(), Some("two"));
+        assert_eq!(history.newer().as_deref(), Some("draft"));
+        assert_eq!(history.newer(), None);
+    }
+
+    #[test]
+    fn test_complete() {
+        let candidates = ["/reply", "/rename", "/reload", "/info"];
+        assert_eq!(
+            complete("/i", &candidates),
+            Completion::Unique("/info".into())
+        );
+        assert_eq!(complete("/x", &candidates), Completion::None);
+        assert_eq!(
+            complete("/re", &candidates),
+            Completion::Partial {
+                prefix: "/re".into(),
+                options: vec!["/reload".into(), "/rename".into(), "/reply".into()],
+            }
+        );
+        assert!(matches!(
+            complete("/ren", &["/rename", "/rename"]),
+            Completion::Unique(_)
+        ));
+    }
+}
@@ -0,0 +1,175 @@
+use crate::Error;
+use crate::entity::Role;
+use russh::keys::ssh_key::rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+/// Usernames starting with this prefix redeem an invite, as in `invite-<token>-<name>`.
+pub const PREFIX: &str = "invite-";
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Invite {
+    role: Role,
+    uses_left: u32,
+    expires_at: SystemTime,
+    // Uses being redeemed by sessions whose newcomer is still being checked
+    #[serde(skip)]
+    reserved: u32,
+}
+
+/// Outstanding invite tokens created with `/invite`.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Invites {
+    invites: HashMap<String, Invite>,
+}
+
+impl Invites {
+    /// Creates an invite and returns its token.
+    pub fn create(&mut self, role: Role, uses: u32, expires_in: Duration) -> String {
+        self.prune();
+        let mut bytes = [0; 8];
+        OsRng.fill_bytes(&mut bytes);
+        let token = hex::encode(bytes);
+        self.invites.insert(
+            token.clone(),
+            Invite {
+                role,
+                uses_left: uses,
+                expires_at: SystemTime::now() + expires_in,
+                reserved: 0,
+            },
+        );
+        token
+    }
+
+    /// Holds one use of the invite for a newcomer while their name and key are
+    /// checked, returning the role it grants. Returns `None` for unknown or
+    /// expired tokens and for invites whose uses are all held.
+    pub fn reserve(&mut self, token: &str) -> Option<Role> {
+        self.prune();
+        let invite = self.invites.get_mut(token)?;
+        if invite.reserved >= invite.uses_left {
+            return None;
+        }
+        invite.reserved += 1;
+        Some(invite.role)
+    }
+
+    /// Uses up the use held with `reserve` once the newcomer is let in,
+    /// or gives it back to the invite otherwise.
+    pub fn settle(&mut self, token: &str, admitted: bool) {
+        let Some(invite) = self.invites.get_mut(token) else {
+            return;
+        };
+        invite.reserved = invite.reserved.saturating_sub(1);
+        if admitted {
+            invite.uses_left = invite.uses_left.saturating_sub(1);
+        }
+        self.prune();
+    }
+
+    pub fn len(&self) -> usize {
+        self.invites.len()
+    }
+
+    fn prune(&mut self) {
+        let now = SystemTime::now();
+        // a held use is settled even once the invite expires
+        self.invites.retain(|_, invite| {
+            invite.reserved > 0 || (invite.uses_left > 0 && invite.expires_at > now)
+        });
+    }
+}
+
+/// Splits a username like `invite-<token>-<name>` into the token and the chosen name.
+pub fn parse_username(user: &str) -> Option<(&str, &str)> {
+    let rest = user.strip_prefix(PREFIX)?;
+    rest.split_once('-')
+        .filter(|(token, name)| !token.is_empty() && !name.is_empty())
+}
+
+/// The username with any invite token left out, for logging logins
+/// that may carry a token still in use.
+pub fn redact_username(user: &str) -> String {
+    match parse_username(user) {
+        Some((_, name)) => format!("{PREFIX}…-{name}"),
+        None if user.starts_with(PREFIX) => format!("{PREFIX}…"),
+        None => user.to_string(),
+    }
+}
+
+/// Parses a duration such as `90s`, `30m`, `24h` or `7d`.
+pub fn parse_duration(s: &str) -> Result<Duration, Error> {
+    let invalid = || Error::InvalidDuration(s.to_string());
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (amount, unit) = s.split_at(split);
+    let amount: u64 = amount.parse().map_err(|_| invalid())?;
+    let seconds = match unit {
+        "s" | "" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    Ok(Duration::from_secs(amount * seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("24h").unwrap(), Duration::from_secs(86400));
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        for invalid in ["", "h", "3w", "-1m"] {
+            assert!(parse_duration(invalid).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn test_parse_username() {
+        assert_eq!(
+            parse_username("invite-0a1b-bob-work"),
+            Some(("0a1b", "bob-work"))
+        );
+        assert_eq!(parse_username("invite-0a1b"), None);
+        assert_eq!(parse_username("bob"), None);
+    }
+
+    #[test]
+    fn test_redact_username() {
+        assert_eq!(redact_username("invite-0a1b-bob"), "invite-…-bob");
+        assert_eq!(redact_username("invite-0a1b"), "invite-…");
+        assert_eq!(redact_username("bob"), "bob");
+    }
+
+    #[test]
+    fn test_redeem_uses() {
+        let mut invites = Invites::default();
+        let token = invites.create(Role::Admin, 2, Duration::from_secs(60));
+        assert_eq!(invites.reserve(&token), Some(Role::Admin));
+        assert_eq!(invites.reserve(&token), Some(Role::Admin));
+        // both uses are held until the newcomers are let in or turned away
+        assert_eq!(invites.reserve(&token), None);
+        invites.settle(&token, true);
+        invites.settle(&token, true);
+        assert_eq!(invites.reserve(&token), None);
+
+        let expired = invites.create(Role::Normal, 1, Duration::ZERO);
+        assert_eq!(invites.reserve(&expired), None);
+        assert_eq!(invites.len(), 0);
+    }
+
+    #[test]
+    fn test_rejected_redemption_keeps_invite() {
+        let mut invites = Invites::default();
+        let token = invites.create(Role::Normal, 1, Duration::from_secs(60));
+        assert_eq!(invites.reserve(&token), Some(Role::Normal));
+        invites.settle(&token, false);
+        assert_eq!(invites.reserve(&token), Some(Role::Normal));
+        invites.settle(&token, true);
+        assert_eq!(invites.len(), 0);
+    }
+}
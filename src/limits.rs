@@ -0,0 +1,174 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// What to do when a key already has as many sessions as allowed.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum SessionPolicy {
+    /// Turn the new session away
+    Reject,
+    /// Close the oldest session of the key to make room
+    CloseOldest,
+}
+
+/// Counts the connections from each address over a sliding window.
+pub struct RateLimiter {
+    max: usize,
+    window: Duration,
+    attempts: HashMap<IpAddr, VecDeque<Instant>>,
+}
+
+impl RateLimiter {
+    pub fn new(max: usize, window: Duration) -> Self {
+        Self {
+            max,
+            window,
+            attempts: HashMap::new(),
+        }
+    }
+
+    /// Records a connection from the address, returning whether it is within the limit.
+    pub fn allow(&mut self, ip: IpAddr, now: Instant) -> bool {
+        let window = self.window;
+        // forget the addresses that have been quiet for a whole window
+        self.attempts.retain(|_, attempts| {
+            while attempts
+                .front()
+                .is_some_and(|attempt| now.duration_since(*attempt) >= window)
+            {
+                attempts.pop_front();
+            }
+            !attempts.is_empty()
+        });
+
+        let attempts = self.attempts.entry(ip).or_default();
+        if attempts.len() >= self.max {
+            return false;
+        }
+        attempts.push_back(now);
+        true
+    }
+}
+
+/// Addresses locked out after too many rejected authentication attempts.
+pub struct Blocklist {
+    max_failures: usize,
+    lockout: Duration,
+    // Rejected attempts within the last lockout period
+    failures: HashMap<IpAddr, Vec<Instant>>,
+    blocked_until: HashMap<IpAddr, Instant>,
+}
+
+impl Blocklist {
+    pub fn new(max_failures: usize, lockout: Duration) -> Self {
+        Self {
+            max_failures,
+            lockout,
+            failures: HashMap::new(),
+            blocked_until: HashMap::new(),
+        }
+    }
+
+    /// Records a rejected attempt, returning the number of recent failures.
+    /// The address is blocked once they reach the limit.
+    pub fn fail(&mut self, ip: IpAddr, now: Instant) -> usize {
+        let lockout = self.lockout;
+        // forget the addresses whose failures are all older than the lockout period
+        self.failures.retain(|_, failures| {
+            failures.retain(|failure| now.duration_since(*failure) < lockout);
+            !failures.is_empty()
+        });
+        let failures = self.failures.entry(ip).or_default();
+        failures.push(now);
+        let count = failures.len();
+        if count >= self.max_failures {
+            self.failures.remove(&ip);
+            self.blocked_until.insert(ip, now + lockout);
+        }
+        count
+    }
+
+    pub fn is_blocked(&mut self, ip: IpAddr, now: Instant) -> bool {
+        self.blocked_until.retain(|_, until| *until > now);
+        self.blocked_until.contains_key(&ip)
+    }
+
+    /// Blocks the address until the given time, as when carrying a block over from a previous process.
+    pub fn block(&mut self, ip: IpAddr, until: Instant) {
+        self.blocked_until.insert(ip, until);
+    }
+
+    /// Lifts a block, returning whether the address was blocked.
+    pub fn unblock(&mut self, ip: IpAddr) -> bool {
+        self.failures.remove(&ip);
+        self.blocked_until.remove(&ip).is_some()
+    }
+
+    /// The blocked addresses along with how long they remain blocked.
+    pub fn blocked(&self, now: Instant) -> Vec<(IpAddr, Duration)> {
+        let mut blocked: Vec<(IpAddr, Duration)> = self
+            .blocked_until
+            .iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(ip, until)| (*ip, until.duration_since(now)))
+            .collect();
+        blocked.sort();
+        blocked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+
+        assert!(limiter.allow(ip, start));
+        assert!(limiter.allow(ip, start + Duration::from_secs(10)));
+        assert!(!limiter.allow(ip, start + Duration::from_secs(20)));
+        assert!(limiter.allow(other, start + Duration::from_secs(20)));
+        // the first connection has left the window
+        assert!(limiter.allow(ip, start + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_blocklist() {
+        let lockout = Duration::from_secs(900);
+        let mut blocklist = Blocklist::new(3, lockout);
+        let start = Instant::now();
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+
+        assert_eq!(blocklist.fail(ip, start), 1);
+        // failures older than the lockout period are forgotten
+        assert_eq!(blocklist.fail(ip, start + lockout), 1);
+        assert_eq!(blocklist.fail(ip, start + lockout), 2);
+        assert!(!blocklist.is_blocked(ip, start + lockout));
+        assert_eq!(blocklist.fail(ip, start + lockout), 3);
+        assert!(blocklist.is_blocked(ip, start + lockout));
+        assert_eq!(blocklist.blocked(start + lockout), [(ip, lockout)]);
+
+        assert!(blocklist.unblock(ip));
+        assert!(!blocklist.is_blocked(ip, start + lockout));
+        assert!(!blocklist.unblock(ip));
+    }
+
+    #[test]
+    fn test_blocklist_forgets_stale_addresses() {
+        let lockout = Duration::from_secs(900);
+        let mut blocklist = Blocklist::new(3, lockout);
+        let start = Instant::now();
+        for i in 0..100 {
+            blocklist.fail(IpAddr::from([192, 0, 2, i]), start);
+        }
+        assert_eq!(blocklist.failures.len(), 100);
+
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(blocklist.fail(ip, start + lockout), 1);
+        assert_eq!(blocklist.failures.len(), 1);
+    }
+}
@@ -0,0 +1,178 @@
+use crate::entity::Role;
+use russh::keys::PublicKey;
+use std::time::SystemTime;
+
+/// A key waiting for an admin to let it in.
+pub struct Request {
+    pub key: PublicKey,
+    pub fingerprint: String,
+    /// The name asked for when joining by invite
+    pub name: Option<String>,
+    pub role: Role,
+    pub requested_at: SystemTime,
+    /// The waiting sessions opened with the key
+    pub ids: Vec<usize>,
+}
+
+/// Join requests from unknown keys, in the order they arrived.
+#[derive(Default)]
+pub struct Lobby {
+    requests: Vec<Request>,
+}
+
+impl Lobby {
+    /// Queues the session, returning false when the lobby is full.
+    pub fn join(
+        &mut self,
+        key: &PublicKey,
+        name: Option<String>,
+        role: Role,
+        id: usize,
+        capacity: usize,
+    ) -> bool {
+        let fingerprint = key.fingerprint(russh::keys::HashAlg::Sha256).to_string();
+        if let Some(request) = self.get_mut(&fingerprint) {
+            // a later invite takes precedence over the earlier request
+            if name.is_some() {
+                request.name = name;
+                request.role = role;
+            }
+            request.ids.push(id);
+            return true;
+        }
+        if self.requests.len() >= capacity {
+            return false;
+        }
+        self.requests.push(Request {
+            key: key.clone(),
+            fingerprint,
+            name,
+            role,
+            requested_at: SystemTime::now(),
+            ids: vec![id],
+        });
+        true
+    }
+
+    pub fn get(&self, fingerprint: &str) -> Option<&Request> {
+        self.requests
+            .iter()
+            .find(|request| request.fingerprint == fingerprint)
+    }
+
+    fn get_mut(&mut self, fingerprint: &str) -> Option<&mut Request> {
+        self.requests
+            .iter_mut()
+            .find(|request| request.fingerprint == fingerprint)
+    }
+
+    pub fn remove(&mut self, fingerprint: &str) -> Option<Request> {
+        let index = self
+            .requests
+            .iter()
+            .position(|request| request.fingerprint == fingerprint)?;
+        Some(self.requests.remove(index))
+    }
+
+    /// Returns a request taken out with `remove`, such as when approving it
+    /// failed, merging it with any request the key made in the meantime.
+    pub fn put_back(&mut self, mut request: Request) {
+        if let Some(index) = self
+            .requests
+            .iter()
+            .position(|later| later.fingerprint == request.fingerprint)
+        {
+            let later = self.requests.remove(index);
+            request.ids.extend(later.ids);
+            if later.name.is_some() {
+                request.name = later.name;
+                request.role = later.role;
+            }
+        }
+        // back in its place, ahead of the requests that came after it
+        let index = self
+            .requests
+            .iter()
+            .position(|other| other.requested_at > request.requested_at)
+            .unwrap_or(self.requests.len());
+        self.requests.insert(index, request);
+    }
+
+    /// The fingerprint of the key a waiting session was opened with.
+    pub fn fingerprint_of(&self, id: usize) -> Option<&str> {
+        self.requests
+            .iter()
+            .find(|request| request.ids.contains(&id))
+            .map(|request| request.fingerprint.as_str())
+    }
+
+    /// Forgets a waiting session, dropping its request once no session is left
+    /// waiting on it so that keys that came and went do not fill the lobby.
+    pub fn leave(&mut self, id: usize) {
+        for request in self.requests.iter_mut() {
+            request.ids.retain(|waiting| *waiting != id);
+        }
+        self.requests.retain(|request| !request.ids.is_empty());
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Request> {
+        self.requests.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> PublicKey {
+        PublicKey::from_openssh(
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIA/wbGoIUsbBHFbnXj2g+23C8sUgYkZTq0TrBm0MMWnx",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_join_and_leave() {
+        let mut lobby = Lobby::default();
+        assert!(lobby.join(&key(), None, Role::Normal, 1, 1));
+        assert!(lobby.join(&key(), Some("dri".to_string()), Role::Admin, 2, 1));
+
+        let fingerprint = lobby.fingerprint_of(2).unwrap().to_string();
+        let request = lobby.get(&fingerprint).unwrap();
+        assert_eq!(request.ids, [1, 2]);
+        assert_eq!(request.name.as_deref(), Some("dri"));
+
+        lobby.leave(1);
+        assert_eq!(lobby.fingerprint_of(1), None);
+        assert!(lobby.remove(&fingerprint).is_some());
+        assert!(lobby.is_empty());
+    }
+
+    #[test]
+    fn test_leave_frees_room() {
+        let mut lobby = Lobby::default();
+        assert!(lobby.join(&key(), None, Role::Normal, 1, 1));
+        lobby.leave(1);
+        assert!(lobby.is_empty());
+        assert!(lobby.join(&key(), None, Role::Normal, 2, 1));
+    }
+
+    #[test]
+    fn test_put_back_merges() {
+        let mut lobby = Lobby::default();
+        assert!(lobby.join(&key(), Some("dri".to_string()), Role::Normal, 1, 2));
+        let fingerprint = lobby.fingerprint_of(1).unwrap().to_string();
+        let request = lobby.remove(&fingerprint).unwrap();
+        assert!(lobby.join(&key(), None, Role::Normal, 2, 2));
+
+        lobby.put_back(request);
+        let request = lobby.get(&fingerprint).unwrap();
+        assert_eq!(request.ids, [1, 2]);
+        assert_eq!(request.name.as_deref(), Some("dri"));
+        assert_eq!(lobby.iter().count(), 1);
+    }
+}
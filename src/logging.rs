@@ -0,0 +1,121 @@
+use log::kv::{self, VisitSource};
+use log::{LevelFilter, Record};
+use std::fmt::Display;
+use std::io::Write;
+
+/// How log records are written to standard error.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// A line of text per record, followed by its fields
+    Text,
+    /// A JSON object per line
+    Json,
+}
+
+/// Sets up the logger. `RUST_LOG` chooses the level per module,
+/// an explicit level applies to every module and takes precedence.
+pub fn init(level: Option<LevelFilter>, format: LogFormat) {
+    let mut builder =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+    if let Some(level) = level {
+        builder.filter_level(level);
+    }
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let timestamp = buf.timestamp().to_string();
+            writeln!(buf, "{}", json_line(record, &timestamp))
+        });
+    }
+    builder.init();
+}
+
+/// Formats the record, along with its fields, as a single JSON object.
+fn json_line(record: &Record, timestamp: &str) -> serde_json::Value {
+    let mut line = serde_json::Map::new();
+    line.insert("timestamp".to_string(), timestamp.into());
+    line.insert("level".to_string(), record.level().as_str().into());
+    line.insert("target".to_string(), record.target().into());
+    line.insert("message".to_string(), record.args().to_string().into());
+    let mut fields = Fields(&mut line);
+    if let Err(e) = record.key_values().visit(&mut fields) {
+        line.insert("fields_error".to_string(), e.to_string().into());
+    }
+    line.into()
+}
+
+struct Fields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let value = match value.to_u64() {
+            Some(number) => number.into(),
+            None => value.to_string().into(),
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// Displays an optional field of a record, or `-` when it is missing.
+pub struct Optional<'a, T>(pub &'a Option<T>);
+
+impl<T: Display> Display for Optional<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(value) => value.fmt(f),
+            None => f.write_str("-"),
+        }
+    }
+}
+
+/// Logs a record about the session handled by an `AppServer`,
+/// with its client id, peer address and key fingerprint attached as fields.
+/// More fields may precede the message, as in `reason = "throttled"; "rejected"`.
+macro_rules! session_log {
+    ($server:expr, $level:ident, $($key:ident $(:$capture:tt)? = $value:expr),+; $($arg:tt)+) => {
+        log::$level!(
+            client = $server.id,
+            peer:% = $crate::logging::Optional(&$server.peer),
+            fingerprint:% = $crate::logging::Optional(&$server.fingerprint),
+            $($key $(:$capture)? = $value),+;
+            $($arg)+
+        )
+    };
+    ($server:expr, $level:ident, $($arg:tt)+) => {
+        log::$level!(
+            client = $server.id,
+            peer:% = $crate::logging::Optional(&$server.peer),
+            fingerprint:% = $crate::logging::Optional(&$server.fingerprint);
+            $($arg)+
+        )
+    };
+}
+pub(crate) use session_log;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_line() {
+        let fields: [(&str, &dyn kv::ToValue); 2] = [("client", &3), ("peer", &"192.0.2.1:22")];
+        let record = Record::builder()
+            .level(log::Level::Warn)
+            .target("publicly")
+            .args(format_args!("auth rejected"))
+            .key_values(&fields)
+            .build();
+
+        assert_eq!(
+            json_line(&record, "2026-01-01T00:00:00Z"),
+            serde_json::json!({
+                "timestamp": "2026-01-01T00:00:00Z",
+                "level": "WARN",
+                "target": "publicly",
+                "message": "auth rejected",
+                "client": 3,
+                "peer": "192.0.2.1:22",
+            })
+        );
+    }
+}
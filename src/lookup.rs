@@ -1,5 +1,6 @@
 use crate::Error;
 use crate::entity::Entity;
+use std::fmt::Display;
 use std::str::FromStr;
 pub enum EntityLookup {
     Name(String),
@@ -21,6 +22,15 @@ impl FromStr for EntityLookup {
     }
 }
 
+impl Display for EntityLookup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EntityLookup::Name(name) => write!(f, "{name}"),
+            EntityLookup::Sha256(digest) => write!(f, "{digest}"),
+        }
+    }
+}
+
 impl EntityLookup {
     pub async fn matches<T: AsRef<Entity>>(&self, entity: T) -> bool {
         let entity = entity.as_ref();
@@ -1,4 +1,5 @@
 use std::collections::{HashMap, HashSet};
+use std::io::Write;
 use std::path::Path;
 use std::sync::Arc;
 
@@ -7,30 +8,68 @@ use clap::Parser;
 use ratatui::backend::TermionBackend;
 use ratatui::layout::Rect;
 use ratatui::termion::event::{Event, Key};
-use ratatui::widgets::{Block, BorderType, Clear, List};
+use ratatui::text::{Span, Text};
+use ratatui::widgets::{Clear, List};
 use ratatui::{Terminal, TerminalOptions, Viewport};
-use ringbuffer::{AllocRingBuffer, RingBuffer};
 use russh::keys::{PublicKey, ssh_key::public::KeyData, ssh_key::rand_core::OsRng};
 use russh::server::{Auth, Config, Handle, Handler, Msg, Server, Session};
 use russh::{Channel, ChannelId, Pty};
 use tokio::sync::RwLock;
 use tui_textarea::TextArea;
 
+use logging::session_log;
+
+mod audit;
 mod authfile;
+mod capabilities;
+mod command;
 mod entity;
 mod error;
+mod history;
+mod input;
+mod invite;
+mod limits;
+mod lobby;
+mod logging;
 mod lookup;
+mod markup;
 mod message;
+mod metrics;
+mod paste;
+mod preferences;
+mod reaction;
+mod restart;
+mod sanitize;
+mod shutdown;
+mod systemd;
 mod terminal_handle;
+mod theme;
 mod ui;
 
+use capabilities::Capabilities;
+pub use command::Command;
 use entity::Entity;
 use error::Error;
-use message::Message;
+use history::{Entry, History, MessageId, MessageTarget};
+use lookup::EntityLookup;
+use message::{Chat, Message};
+use preferences::Preferences;
 use terminal_handle::TerminalHandle;
 
 type SshTerminal = Terminal<TermionBackend<TerminalHandle>>;
 
+// The longest to wait for sessions to end once they are closed at shutdown
+const CLOSE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+const WAITING_TITLE: &str = "[waiting for approval]";
+
+/// How a key that passed authentication joins the chat.
+enum Admission {
+    Member(Arc<Entity>),
+    /// Unknown keys wait in the lobby until an admin approves them
+    Waiting,
+}
+
 // wraps a type T as Arc<Mutex<T>> so that it can be locked
 // in asynchronous coroutines
 fn new_atomic<T>(object: T) -> Atomic<T> {
@@ -39,10 +78,17 @@ fn new_atomic<T>(object: T) -> Atomic<T> {
 
 type Atomic<T> = Arc<RwLock<T>>;
 
+/// Identifies a session in the log once its handler is gone.
+struct SessionFields {
+    id: usize,
+    peer: Option<std::net::SocketAddr>,
+    fingerprint: Option<String>,
+}
+
 /// App contains data strictly related to the chat.
 /// It is not responsible for authorization.
 struct App {
-    pub history: AllocRingBuffer<Message>,
+    pub history: History,
 }
 
 pub struct Client {
@@ -51,10 +97,24 @@ pub struct Client {
     terminal: SshTerminal,
     textarea: TextArea<'static>,
     statusline: String,
+    // When set, only the thread containing this message is shown
+    thread: Option<MessageId>,
+    paste: paste::Paste,
+    input_history: input::InputHistory,
+    preferences: Preferences,
+    capabilities: Capabilities,
+    // Environment variables sent by the client, kept to refine the capabilities
+    environment: Vec<(String, String)>,
+    // The fingerprint of the key while the session waits in the lobby
+    waiting: Option<String>,
 }
 
 #[derive(Clone)]
 struct AppServer {
+    // Locked in the order they are declared in whenever several are held at once
+    // Held for reading while a session changes the state carried over on restart,
+    // set once the state is being handed over to stop further changes
+    handing_over: Atomic<bool>,
     keychain: Atomic<Vec<Arc<Entity>>>,
     key_data_pool: Atomic<HashSet<KeyData>>,
     key_data_to_user: Atomic<HashMap<KeyData, Arc<Entity>>>,
@@ -64,11 +124,32 @@ struct AppServer {
 
     id: usize,
     args: Args,
+    // The port actually listened on, which is not `--port` when the socket is passed in
+    port: u16,
     app: Atomic<App>,
+    audit: Atomic<audit::AuditLog>,
+    invites: Atomic<invite::Invites>,
+    lobby: Atomic<lobby::Lobby>,
+    // Locked synchronously as new clients are created outside of any coroutine
+    connections: Arc<std::sync::Mutex<limits::RateLimiter>>,
+    blocklist: Arc<std::sync::Mutex<limits::Blocklist>>,
+    metrics: Arc<metrics::Metrics>,
+    // Set once a shutdown is requested with /shutdown
+    shutdown: Arc<tokio::sync::watch::Sender<Option<shutdown::Shutdown>>>,
+
+    // The address the current client connected from
+    peer: Option<std::net::SocketAddr>,
+    // The fingerprint of the key the current client last offered
+    fingerprint: Option<String>,
+    // Set when the address has connected too often to be let in
+    throttled: bool,
+    // The user of the last unknown key turned away, counted as a single failed
+    // attempt should the connection close without being let in
+    rejected: Option<String>,
 }
 
 impl AppServer {
-    pub async fn run(&mut self) -> Result<(), anyhow::Error> {
+    pub async fn run(&mut self, listener: tokio::net::TcpListener) -> Result<(), anyhow::Error> {
         let mut methods = russh::MethodSet::empty();
         methods.push(russh::MethodKind::PublicKey);
 
@@ -83,11 +164,184 @@ impl AppServer {
             )?],
             ..Default::default()
         };
-        self.run_on_address(Arc::new(config), (self.args.host.clone(), self.args.port))
-            .await?;
+        let grace = self.args.shutdown_grace;
+        let mut requested = self.shutdown.subscribe();
+        // the server borrows self until it stops, the state is reached through these
+        let (handing_over, keychain, app, audit, invites, blocklist) = (
+            self.handing_over.clone(),
+            self.keychain.clone(),
+            self.app.clone(),
+            self.audit.clone(),
+            self.invites.clone(),
+            self.blocklist.clone(),
+        );
+        let data_dir = self.args.data_dir.clone();
+        // listening before systemd is told the server is ready, so no signal is missed
+        let mut stop_signals = shutdown::Signals::new()?;
+        let mut restart_signals = restart::Signals::new()?;
+        // sessions are disconnected as soon as every sender is gone,
+        // this one keeps them open to see the shutdown through
+        let running = tokio::sync::broadcast::channel(1).0;
+        let mut server = Box::pin(self.serve(Arc::new(config), &listener, running.clone()));
+        systemd::notify("READY=1");
+        restart::report_ready();
+        // no more connections are accepted once a shutdown is requested
+        let shutdown = loop {
+            tokio::select! {
+                result = &mut server => {
+                    result?;
+                    return Ok(());
+                }
+                _ = requested.changed() => break requested.borrow().clone().unwrap_or_default(),
+                _ = stop_signals.requested() => {
+                    break shutdown::Shutdown {
+                        delay: grace,
+                        ..Default::default()
+                    };
+                }
+                _ = restart_signals.requested() => {
+                    // changes under way are finished first, none are made after
+                    *handing_over.write().await = true;
+                    let state = save_state(&keychain, &app, &invites, &blocklist).await;
+                    // the new process carries on with the audit log as it finds it,
+                    // nothing is appended here until it is known whether it took over
+                    let mut audit = audit.write().await;
+                    // a failed restart leaves this process serving as before
+                    match restart::hand_over(&listener, &state, Path::new(&data_dir)).await {
+                        Ok(pid) => {
+                            audit.hand_over();
+                            log::info!("handed the listener over to process {pid}");
+                            // systemd keeps tracking the service through the new process
+                            systemd::notify(&format!("MAINPID={pid}"));
+                            break shutdown::Shutdown {
+                                restart: true,
+                                ..Default::default()
+                            };
+                        }
+                        Err(e) => {
+                            log::error!("failed to restart: {}", error::describe(&e));
+                            *handing_over.write().await = false;
+                        }
+                    }
+                }
+            }
+        };
+        drop(server);
+        self.shut_down(shutdown, running, stop_signals).await;
         Ok(())
     }
 
+    /// Tells every session the server is stopping, waits out the delay
+    /// and then closes them all.
+    async fn shut_down(
+        &self,
+        shutdown: shutdown::Shutdown,
+        running: tokio::sync::broadcast::Sender<String>,
+        mut signals: shutdown::Signals,
+    ) {
+        if !shutdown.restart {
+            systemd::notify("STOPPING=1");
+        }
+        let notice = shutdown.notice();
+        log::info!("{notice}");
+        self.app
+            .write()
+            .await
+            .history
+            .enqueue(Message::Notice(notice.clone()));
+        // sessions in the lobby only see their statusline
+        for client in self.clients.write().await.values_mut() {
+            client.statusline = sanitize::line(&notice);
+        }
+        self.render().await;
+
+        if !shutdown.delay.is_zero() {
+            tokio::select! {
+                _ = tokio::time::sleep(shutdown.delay) => {}
+                _ = signals.requested() => log::info!("cutting the shutdown delay short"),
+            }
+        }
+        if self.args.commit_on_shutdown {
+            match self.commit().await {
+                Ok(count) => log::info!("committed {count} keys to {}", self.args.authfile),
+                Err(e) => log::error!(
+                    "failed to commit the keychain before shutting down: {}",
+                    error::describe(&e)
+                ),
+            }
+        }
+
+        for (id, client) in self.clients.write().await.iter_mut() {
+            if let Err(e) = farewell(client) {
+                log::error!(client = *id; "failed to clean up the terminal of the leaving client: {e}");
+            }
+            // a zero exit status tells the client the session ended as intended
+            let _ = client.handle.exit_status_request(client.channel, 0).await;
+            if let Err(()) = client.handle.close(client.channel).await {
+                log::error!(client = *id; "failed to close the session");
+            }
+        }
+        // sessions remove themselves from the clients once their connection ends
+        let deadline = tokio::time::Instant::now() + CLOSE_TIMEOUT;
+        while !self.clients.read().await.is_empty() && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        // disconnect whatever is left, including connections yet to open a session
+        let _ = running.send(notice);
+        log::info!("shut down");
+    }
+
+    /// Accepts connections until the listener fails, running each session in a
+    /// task of its own until it ends or is disconnected with the reason sent on
+    /// `running`. Unlike `Server::run_on_socket`, this knows which session
+    /// an error comes from.
+    async fn serve(
+        &mut self,
+        config: Arc<Config>,
+        listener: &tokio::net::TcpListener,
+        running: tokio::sync::broadcast::Sender<String>,
+    ) -> std::io::Result<()> {
+        loop {
+            let (socket, peer) = listener.accept().await?;
+            let handler = self.new_client(Some(peer));
+            // the handler is gone by the time its session fails,
+            // the earlier records of the client carry the fingerprint
+            let session = SessionFields {
+                id: handler.id,
+                peer: handler.peer,
+                fingerprint: None,
+            };
+            let mut disconnect = running.subscribe();
+            let config = config.clone();
+            tokio::spawn(async move {
+                let running = match russh::server::run_stream(config, socket, handler).await {
+                    Ok(running) => running,
+                    Err(e) => {
+                        session_log!(session, error, "session error: {}", error::describe(&e));
+                        return;
+                    }
+                };
+                let handle = running.handle();
+                tokio::select! {
+                    reason = disconnect.recv() => {
+                        let reason = reason.unwrap_or_default();
+                        let disconnected = handle
+                            .disconnect(russh::Disconnect::ByApplication, reason, String::new())
+                            .await;
+                        if disconnected.is_err() {
+                            session_log!(session, debug, "failed to disconnect the session");
+                        }
+                    }
+                    result = running => {
+                        if let Err(e) = result {
+                            session_log!(session, error, "session error: {}", error::describe(&e));
+                        }
+                    }
+                }
+            });
+        }
+    }
+
     async fn reload(&mut self) -> Result<(), Error> {
         let new_keychain = authfile::read(Path::new(&self.args.authfile)).await?;
 
@@ -95,10 +349,10 @@ impl AppServer {
         {
             let mut keychain = self.keychain.write().await;
             let mut key_data_pool = self.key_data_pool.write().await;
-            let mut key_data_to_id = self.key_data_to_id.write().await;
             let mut key_data_to_user = self.key_data_to_user.write().await;
-            let mut clients = self.clients.write().await;
+            let mut key_data_to_id = self.key_data_to_id.write().await;
             let mut id_to_user = self.id_to_user.write().await;
+            let mut clients = self.clients.write().await;
 
             // find all strays
             for stray in key_data_pool.difference(&new_keychain.key_pool) {
@@ -108,9 +362,11 @@ impl AppServer {
 
                 // these IDs are now invalid
                 for id in ids.iter() {
-                    let client = &clients[id];
-                    if let Err(()) = client.handle.close(client.channel).await {
-                        return Err(Error::ClientDisconnectFailed(*id));
+                    if let Some(client) = clients.get_mut(id) {
+                        self.see_off(*id, client);
+                        if let Err(()) = client.handle.close(client.channel).await {
+                            return Err(Error::ClientDisconnectFailed(*id));
+                        }
                     }
                     clients.remove(id);
                     id_to_user.remove(id);
@@ -126,14 +382,496 @@ impl AppServer {
                 new_key_data_to_user.insert(entity.key_data(), entity.clone());
             }
 
+            // live sessions carry on as the members their keys now belong to
+            for (id, entity) in id_to_user.iter_mut() {
+                if let Some(new_entity) = new_key_data_to_user.get(&entity.key_data()) {
+                    *entity = new_entity.clone();
+                } else if let Some(client) = clients.get_mut(id) {
+                    self.see_off(*id, client);
+                    if let Err(()) = client.handle.close(client.channel).await {
+                        return Err(Error::ClientDisconnectFailed(*id));
+                    }
+                    clients.remove(id);
+                }
+            }
+            id_to_user.retain(|_, entity| new_key_data_to_user.contains_key(&entity.key_data()));
+            key_data_to_id.retain(|key_data, _| new_key_data_to_user.contains_key(key_data));
+
             *key_data_to_user = new_key_data_to_user;
             *keychain = new_keychain.entities;
             *key_data_pool = new_keychain.key_pool;
         }
-        log::info!("authfile synchronized to memory");
+        self.prune_reactions().await;
+        session_log!(self, info, "authfile synchronized to memory");
         Ok(())
     }
 
+    /// Removes reactions left by keys no longer present in the keychain.
+    async fn prune_reactions(&self) {
+        let fingerprints: HashSet<String> = self
+            .keychain
+            .read()
+            .await
+            .iter()
+            .map(|entity| entity.fingerprint())
+            .collect();
+        self.app
+            .write()
+            .await
+            .history
+            .retain_reactors(|fingerprint| fingerprints.contains(fingerprint));
+    }
+
+    /// Adds a new member to the in-memory keychain, rejecting keys
+    /// already present and names already taken.
+    async fn add_entity(&self, entity: Entity) -> Result<Arc<Entity>, Error> {
+        let mut keychain = self.keychain.write().await;
+        let mut key_data_pool = self.key_data_pool.write().await;
+        let mut key_data_to_user = self.key_data_to_user.write().await;
+
+        check_new_entity(&keychain, &key_data_pool, &entity).await?;
+
+        let key_data = entity.key_data();
+        let entity = Arc::new(entity);
+        keychain.push(entity.clone());
+        key_data_pool.insert(key_data.clone());
+        key_data_to_user.insert(key_data, entity.clone());
+        Ok(entity)
+    }
+
+    /// Writes the in-memory keychain to the Authfile, returning the number of keys written.
+    async fn commit(&self) -> Result<usize, Error> {
+        let keychain = self.keychain.read().await;
+        let mut pubkeys = vec![];
+        for entity in keychain.iter() {
+            let ent_str = entity.to_pubkey().await.to_string();
+            pubkeys.push(ent_str);
+        }
+        let count = pubkeys.len();
+        let pubkeys = pubkeys.join("\n");
+        let authfile = &self.args.authfile;
+        let mut tmpfile = authfile.clone();
+        tmpfile.push('~');
+        std::fs::write(&tmpfile, pubkeys).map_err(|source| Error::KeychainNotWritten {
+            source,
+            path: tmpfile.clone(),
+        })?;
+        std::fs::rename(&tmpfile, authfile).map_err(|source| Error::AuthfileNotReplaced {
+            source,
+            path: authfile.clone(),
+        })?;
+        Ok(count)
+    }
+
+    /// Adds the key of a newcomer connecting as `invite-<token>-<name>`,
+    /// returning the new member if the invite is valid. The invite is only
+    /// used up once the name and key have been accepted.
+    async fn redeem_invite(&self, user: &str, key: &PublicKey) -> Option<Admission> {
+        let (token, name) = invite::parse_username(user)?;
+        let handing_over = self.handing_over.clone();
+        let handing_over = handing_over.read().await;
+        if *handing_over {
+            session_log!(
+                self,
+                warn,
+                reason = "restarting";
+                "rejected an invite while the server restarts"
+            );
+            return None;
+        }
+        let fingerprint = key.fingerprint(russh::keys::HashAlg::Sha256).to_string();
+        let Some(role) = self.invites.write().await.reserve(token) else {
+            session_log!(
+                self,
+                warn,
+                reason = "invalid invite";
+                "rejected an unknown or expired invite"
+            );
+            return None;
+        };
+        // the invites are not locked while the keychain is, the use is held instead
+        let admitted = self.admit_invited(key, name, role).await;
+        self.invites.write().await.settle(token, admitted.is_ok());
+        let admission = match admitted {
+            Ok(admission) => admission,
+            Err(e) => {
+                session_log!(
+                    self,
+                    warn,
+                    reason = "invite refused";
+                    "rejected the invite redeemed as {name:?}: {e}"
+                );
+                return None;
+            }
+        };
+
+        let (name, outcome) = match &admission {
+            Admission::Member(entity) => (entity.name().await, "ok"),
+            Admission::Waiting => (sanitize::name(name), "pending approval"),
+        };
+        let recorded = self.audit.write().await.append(
+            &name,
+            &fingerprint,
+            "redeem invite",
+            &format!("{name} as {role}"),
+            outcome,
+        );
+        if let Err(e) = recorded {
+            session_log!(
+                self,
+                error,
+                "failed to record the invite redeemed by {name} in the audit log: {}",
+                error::describe(&e)
+            );
+        }
+        match admission {
+            Admission::Waiting => self.announce_waiting(&fingerprint).await,
+            Admission::Member(_) => {
+                if self.args.commit_invites
+                    && let Err(e) = self.commit().await
+                {
+                    session_log!(
+                        self,
+                        error,
+                        "failed to commit the keychain after {name} joined by invite: {}",
+                        error::describe(&e)
+                    );
+                }
+            }
+        }
+        Some(admission)
+    }
+
+    /// Adds a newcomer with the role their invite grants, or puts them
+    /// in the lobby when invites need approval.
+    async fn admit_invited(
+        &self,
+        key: &PublicKey,
+        name: &str,
+        role: entity::Role,
+    ) -> Result<Admission, Error> {
+        let entity = Entity::new(key.clone(), name, role);
+        if !self.args.approve_invites {
+            return Ok(Admission::Member(self.add_entity(entity).await?));
+        }
+        // checked before the invite is used up, approval checks again
+        check_new_entity(
+            &self.keychain.read().await,
+            &*self.key_data_pool.read().await,
+            &entity,
+        )
+        .await?;
+        let name = entity.name().await;
+        let joined =
+            self.lobby
+                .write()
+                .await
+                .join(key, Some(name), role, self.id, self.args.max_pending);
+        if !joined {
+            return Err(Error::LobbyFull);
+        }
+        Ok(Admission::Waiting)
+    }
+
+    /// Puts the session in the lobby to wait for an admin to approve its key.
+    async fn queue(
+        &self,
+        key: &PublicKey,
+        name: Option<String>,
+        role: entity::Role,
+    ) -> Option<Admission> {
+        let joined = self
+            .lobby
+            .write()
+            .await
+            .join(key, name, role, self.id, self.args.max_pending);
+        let fingerprint = key.fingerprint(russh::keys::HashAlg::Sha256).to_string();
+        if !joined {
+            session_log!(self, warn, reason = "lobby full"; "rejected the key: the lobby is full");
+            return None;
+        }
+        self.announce_waiting(&fingerprint).await;
+        Some(Admission::Waiting)
+    }
+
+    /// Lets the admins know a key has joined the lobby.
+    async fn announce_waiting(&self, fingerprint: &str) {
+        session_log!(self, info, "the key is waiting in the lobby");
+        self.notify_admins(&format!("{fingerprint} is waiting to join, send /pending"))
+            .await;
+        self.render().await;
+    }
+
+    /// Shows the text in the statusline of every admin online.
+    async fn notify_admins(&self, text: &str) {
+        let id_to_user = self.id_to_user.read().await;
+        let mut clients = self.clients.write().await;
+        for (id, client) in clients.iter_mut() {
+            let Some(entity) = id_to_user.get(id) else {
+                continue;
+            };
+            if entity.role().await == entity::Role::Admin {
+                client.statusline = sanitize::line(text);
+            }
+        }
+    }
+
+    /// Turns the waiting sessions of a newly approved key into member sessions,
+    /// returning how many were still connected.
+    async fn admit(&self, entity: &Arc<Entity>, ids: &[usize]) -> usize {
+        let title = entity.title().await;
+        let preferences = Preferences::load(Path::new(&self.args.data_dir), &entity.fingerprint());
+        let mut admitted = 0;
+        {
+            let mut key_data_to_id = self.key_data_to_id.write().await;
+            let mut id_to_user = self.id_to_user.write().await;
+            let mut clients = self.clients.write().await;
+            for id in ids {
+                let Some(client) = clients.get_mut(id) else {
+                    continue;
+                };
+                client.waiting = None;
+                client.preferences = preferences.clone();
+                let block = ui::textarea_block(title.clone(), &client.capabilities);
+                client.textarea.set_block(block);
+                id_to_user.insert(*id, entity.clone());
+                key_data_to_id
+                    .entry(entity.key_data())
+                    .or_default()
+                    .push(*id);
+                admitted += 1;
+            }
+        }
+        if admitted > 0 {
+            let message = Message::Announce {
+                action: message::Announcement::Joined,
+                persona: entity.persona(),
+            };
+            self.app.write().await.history.enqueue(message);
+        }
+        admitted
+    }
+
+    /// The title of the current session's textarea.
+    async fn title(&self) -> String {
+        let entity = self.id_to_user.read().await.get(&self.id).cloned();
+        match entity {
+            Some(entity) => entity.title().await,
+            None => WAITING_TITLE.to_string(),
+        }
+    }
+
+    /// Finds the member matching the lookup, refusing to pick one
+    /// when several members match.
+    async fn find(&self, lookup: &EntityLookup) -> Result<Arc<Entity>, Error> {
+        let mut found: Vec<Arc<Entity>> = vec![];
+        for entity in self.keychain.read().await.iter() {
+            if lookup.matches(entity).await
+                && !found.iter().any(|member| member.same_identity(entity))
+            {
+                found.push(entity.clone());
+            }
+        }
+        match &found[..] {
+            [] => Err(Error::NoSuchMember(lookup.to_string())),
+            [entity] => Ok(entity.clone()),
+            _ => Err(Error::AmbiguousMember {
+                lookup: lookup.to_string(),
+                candidates: found
+                    .iter()
+                    .map(|entity| entity.fingerprint())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            }),
+        }
+    }
+
+    /// Every key of the member the entity belongs to.
+    async fn identity_keys(&self, entity: &Entity) -> Vec<Arc<Entity>> {
+        self.keychain
+            .read()
+            .await
+            .iter()
+            .filter(|key| key.same_identity(entity))
+            .cloned()
+            .collect()
+    }
+
+    /// The ids of the sessions still connected, by key.
+    async fn online_ids(&self) -> HashMap<KeyData, Vec<usize>> {
+        let key_data_to_id = self.key_data_to_id.read().await;
+        let clients = self.clients.read().await;
+        key_data_to_id
+            .iter()
+            .map(|(key_data, ids)| {
+                let ids = ids.iter().copied().filter(|id| clients.contains_key(id));
+                (key_data.clone(), ids.collect())
+            })
+            .collect()
+    }
+
+    /// The number of sessions connected with any of the member's keys.
+    async fn online_sessions(&self, entity: &Entity) -> usize {
+        let online = self.online_ids().await;
+        self.identity_keys(entity)
+            .await
+            .iter()
+            .filter_map(|key| online.get(&key.key_data()))
+            .map(Vec::len)
+            .sum()
+    }
+
+    fn is_blocked(&self) -> bool {
+        let Some(peer) = self.peer else {
+            return false;
+        };
+        match self.blocklist.lock() {
+            Ok(mut blocklist) => blocklist.is_blocked(peer.ip(), std::time::Instant::now()),
+            Err(e) => {
+                session_log!(self, error, "failed to lock the blocklist: {e}");
+                false
+            }
+        }
+    }
+
+    /// Sees off a client whose session is being closed, which may be another's.
+    fn see_off(&self, id: usize, client: &mut Client) {
+        if let Err(e) = farewell(client) {
+            session_log!(
+                self,
+                error,
+                leaving = id;
+                "failed to clean up the terminal of the leaving client: {e}"
+            );
+        }
+    }
+
+    /// Counts a connection that was never let in against the address it came from,
+    /// the user being already redacted.
+    fn record_failure(&self, user: &str) {
+        let Some(peer) = self.peer else {
+            return;
+        };
+        let failures = match self.blocklist.lock() {
+            Ok(mut blocklist) => blocklist.fail(peer.ip(), std::time::Instant::now()),
+            Err(e) => {
+                session_log!(self, error, "failed to lock the blocklist: {e}");
+                return;
+            }
+        };
+        session_log!(
+            self,
+            warn,
+            "connection for user {user:?} closed without being let in, \
+            {failures} recent failures from the address"
+        );
+        if failures >= self.args.max_auth_failures {
+            session_log!(
+                self,
+                warn,
+                "blocked {} for {}s after {failures} failed attempts",
+                peer.ip(),
+                self.args.lockout.as_secs()
+            );
+        }
+    }
+
+    /// Applies the session limit of the key before it opens another session,
+    /// returning false when the new session is to be turned away.
+    async fn make_room(&self, entity: &Entity) -> bool {
+        let online = self.online_ids().await;
+        let Some(ids) = online.get(&entity.key_data()) else {
+            return true;
+        };
+        if ids.len() < self.args.max_sessions_per_key {
+            return true;
+        }
+        match self.args.session_policy {
+            limits::SessionPolicy::Reject => {
+                session_log!(
+                    self,
+                    warn,
+                    reason = "session limit";
+                    "rejected the session: the key already has {} sessions",
+                    ids.len()
+                );
+                false
+            }
+            limits::SessionPolicy::CloseOldest => {
+                let mut key_data_to_id = self.key_data_to_id.write().await;
+                let mut id_to_user = self.id_to_user.write().await;
+                let mut clients = self.clients.write().await;
+                // ids are handed out in increasing order
+                let excess = ids.len() + 1 - self.args.max_sessions_per_key;
+                for id in ids.iter().take(excess) {
+                    if let Some(ids) = key_data_to_id.get_mut(&entity.key_data()) {
+                        ids.retain(|other| other != id);
+                    }
+                    id_to_user.remove(id);
+                    let Some(mut client) = clients.remove(id) else {
+                        continue;
+                    };
+                    self.see_off(*id, &mut client);
+                    session_log!(self, info, "closing session {id} of the key to make room");
+                    if let Err(()) = client.handle.close(client.channel).await {
+                        session_log!(self, error, "failed to close session {id}");
+                    }
+                }
+                true
+            }
+        }
+    }
+
+    /// Removes the keys from the keychain and disconnects their sessions,
+    /// returning the number of sessions disconnected.
+    async fn remove_keys(&self, keys: &[Arc<Entity>]) -> Result<usize, Error> {
+        let mut keychain = self.keychain.write().await;
+        let mut key_data_pool = self.key_data_pool.write().await;
+        let mut key_data_to_user = self.key_data_to_user.write().await;
+        let mut key_data_to_id = self.key_data_to_id.write().await;
+        let mut clients = self.clients.write().await;
+
+        let mut disconnected = 0;
+        for key in keys {
+            let key_data = key.key_data();
+            keychain.retain(|member| member.key_data() != key_data);
+            key_data_pool.remove(&key_data);
+            key_data_to_user.remove(&key_data);
+            let Some(ids) = key_data_to_id.remove(&key_data) else {
+                continue;
+            };
+            for id in ids {
+                let Some(client) = clients.get_mut(&id) else {
+                    continue;
+                };
+                self.see_off(id, client);
+                if let Err(()) = client.handle.close(client.channel).await {
+                    return Err(Error::ClientDisconnectFailed(id));
+                }
+                clients.remove(&id);
+                disconnected += 1;
+            }
+        }
+        Ok(disconnected)
+    }
+
+    /// Updates the textarea titles of every session of the member after a change to their persona.
+    async fn refresh_titles(&self, entity: &Entity) {
+        let title = entity.title().await;
+        let keys = self.identity_keys(entity).await;
+        let key_data_to_id = self.key_data_to_id.read().await;
+        let mut clients = self.clients.write().await;
+        for key in keys {
+            for id in key_data_to_id.get(&key.key_data()).into_iter().flatten() {
+                let Some(client) = clients.get_mut(id) else {
+                    continue;
+                };
+                let block = ui::textarea_block(title.clone(), &client.capabilities);
+                client.textarea.set_block(block);
+            }
+        }
+    }
+
     async fn entity(&self) -> Arc<Entity> {
         self.id_to_user.read().await[&self.id].clone()
     }
@@ -144,22 +882,146 @@ impl AppServer {
         self.app.write().await.history.enqueue(message);
     }
 
+    /// Decides whether to let the key in, as a member or into the lobby.
+    async fn authenticate(&mut self, user: &str, key: &PublicKey) -> Auth {
+        self.fingerprint = Some(key.fingerprint(russh::keys::HashAlg::Sha256).to_string());
+        if self.throttled {
+            session_log!(
+                self,
+                warn,
+                reason = "throttled";
+                "rejected the session: too many connections from the address"
+            );
+            return Auth::reject();
+        }
+        if self.is_blocked() {
+            session_log!(
+                self,
+                warn,
+                reason = "blocked";
+                "rejected the session: the address is locked out after failed attempts"
+            );
+            return Auth::reject();
+        }
+        if self.clients.read().await.len() >= self.args.max_clients {
+            session_log!(
+                self,
+                warn,
+                reason = "server full";
+                "rejected the session: the server is full"
+            );
+            return Auth::reject();
+        }
+
+        // Search for the key in our keychain, then among the invites
+        let known = self
+            .key_data_to_user
+            .read()
+            .await
+            .get(key.key_data())
+            .cloned();
+        let admission = match known {
+            Some(entity) => Some(Admission::Member(entity)),
+            None => self.redeem_invite(user, key).await,
+        };
+        let admission = match admission {
+            None if self.args.lobby => self.queue(key, None, entity::Role::Normal).await,
+            admission => admission,
+        };
+        if let Some(Admission::Waiting) = admission {
+            self.rejected = None;
+            return Auth::Accept;
+        }
+        if let Some(Admission::Member(entity)) = admission {
+            if !self.make_room(&entity).await {
+                return Auth::reject();
+            }
+            // freeze everything, again
+            let mut key_data_to_id = self.key_data_to_id.write().await;
+            let mut id_to_user = self.id_to_user.write().await;
+
+            id_to_user.insert(self.id, entity);
+
+            key_data_to_id
+                .entry(key.key_data().clone())
+                .or_default()
+                .push(self.id);
+
+            self.rejected = None;
+            return Auth::Accept;
+        }
+        // an invite refused after its token checked out keeps its uses
+        let user = invite::redact_username(user);
+        session_log!(
+            self,
+            warn,
+            reason = "unknown key";
+            "rejected the key offered for user {user:?}"
+        );
+        // clients may offer several keys before the one that is known
+        self.rejected = Some(user);
+        Auth::reject()
+    }
+
     async fn render(&self) {
         let clients = self.clients.clone();
-        let history: Vec<Message> = self.app.read().await.history.to_vec();
+        let metrics = self.metrics.clone();
+        let history: Vec<Entry> = self.app.read().await.history.to_vec();
 
         tokio::spawn(async move {
+            let started = std::time::Instant::now();
+            let entries: HashMap<MessageId, &Entry> =
+                history.iter().map(|entry| (entry.id, entry)).collect();
+            let roots = history::thread_roots(&history);
+
             for (id, client) in clients.write().await.iter_mut() {
+                let thread_root = client.thread.and_then(|thread| roots.get(&thread));
+                let theme = client.preferences.theme;
+
                 // build the message history paragraphs for each client
                 let mut paragraphs = Vec::with_capacity(history.len());
-                for message in history.iter() {
-                    if let Message::Dossier { requested_by, .. } = message
+                if let Some(fingerprint) = &client.waiting {
+                    // sessions in the lobby see none of the history
+                    let mut notice = Text::styled(
+                        format!(
+                            "your key {fingerprint}\nis waiting for an admin to let you in\npress Ctrl+C to leave"
+                        ),
+                        theme.announcement,
+                    );
+                    client.capabilities.adapt(&mut notice);
+                    paragraphs.push(notice);
+                }
+                for entry in history.iter().filter(|_| client.waiting.is_none()) {
+                    if let Message::Dossier { requested_by, .. } = &entry.message
                         && requested_by != id
                     {
                         // show a dossier only to the admin requesting it
                         continue;
                     }
-                    let text_content = message.text_content().await;
+                    if let Some(thread_root) = thread_root
+                        && roots.get(&entry.id) != Some(thread_root)
+                    {
+                        continue;
+                    }
+                    let parent = match &entry.message {
+                        Message::Chat(Chat {
+                            parent: Some(parent),
+                            ..
+                        }) => entries.get(parent).copied(),
+                        _ => None,
+                    };
+                    let mut text_content = entry
+                        .message
+                        .text_content(entry.id, parent, theme, &client.capabilities)
+                        .await;
+                    if client.preferences.timestamps
+                        && let Some(first) = text_content.lines.first_mut()
+                    {
+                        first
+                            .spans
+                            .insert(0, Span::styled(format!("{} ", entry.clock()), theme.dim));
+                    }
+                    client.capabilities.adapt(&mut text_content);
                     paragraphs.push(text_content);
                 }
                 paragraphs.reverse();
@@ -168,251 +1030,851 @@ impl AppServer {
 
                 let res = client.terminal.draw(|f| {
                     // clear the screen
-                    let layout = ui::layout(f);
+                    let layout = ui::layout(f, client.preferences.compact);
 
                     f.render_widget(paragraphs.clone(), layout[0]);
                     f.render_widget(&client.textarea, layout[1]);
                     f.render_widget(&client.statusline, layout[2]);
                 });
                 if let Err(error) = res {
-                    log::error!(
-                        "failed to render the chat interface for client {}: {:#?}",
-                        client.channel,
-                        error
-                    )
+                    log::error!(client = *id; "failed to render the chat interface: {error}")
                 }
             }
+            metrics.rendered(started.elapsed());
         });
     }
 
-    async fn run_command(&mut self, command: Command) -> Result<(), Error> {
-        match command {
+    /// Runs the command, returning the confirmation to show in the statusline
+    /// or `None` when its result is visible in the chat history instead.
+    async fn run_command(&mut self, command: Command) -> Result<Option<String>, Error> {
+        let confirmation = match command {
             Command::Add(entity) => {
-                log::debug!("attempting to add {entity:#?}");
-                let mut keychain = self.keychain.write().await;
-                let mut key_data_pool = self.key_data_pool.write().await;
-                let mut key_data_to_user = self.key_data_to_user.write().await;
-
-                let key_data = entity.key_data();
-
-                let entity = Arc::new(entity);
-                keychain.push(entity.clone());
-                key_data_pool.insert(key_data.clone());
-                key_data_to_user.insert(key_data, entity);
+                session_log!(self, debug, "adding {}", entity.fingerprint());
+                let entity = self.add_entity(entity).await?;
+                Some(format!(
+                    "added {} as {} ({}), send /commit to keep them",
+                    entity.name().await,
+                    entity.role().await,
+                    entity.fingerprint()
+                ))
             }
             Command::Rename { from, to } => {
-                for ent in self.keychain.read().await.iter() {
-                    if ent.name().await != from {
-                        continue;
-                    }
-
-                    ent.set_name(&to).await;
-
-                    let Some(ids) = self
-                        .key_data_to_id
-                        .read()
-                        .await
-                        .get(&ent.key_data())
-                        .cloned()
-                    else {
-                        log::warn!(
-                            "while updating client display name in textareas: found no client id with the key: {}",
-                            ent.fingerprint()
-                        );
-                        return Ok(());
-                    };
-
-                    let title = ent.title().await;
-                    let block = Block::bordered()
-                        .border_type(BorderType::Rounded)
-                        .title(title);
-
-                    let mut clients = self.clients.write().await;
-
-                    for id in ids {
-                        let Some(client) = clients.get_mut(&id) else {
-                            log::warn!(
-                                "failed to get handle on client with id: {id}, considering them disconnected"
-                            );
-                            continue;
-                        };
-                        client.textarea.set_block(block.clone());
-                    }
+                let entity = self.find(&EntityLookup::Name(from.clone())).await?;
+                let sanitized = sanitize::name(&to);
+                if sanitized.is_empty() {
+                    return Err(Error::InvalidName(to));
                 }
+                // checked and taken under one lock, so no one else claims the name in between
+                let keychain = self.keychain.write().await;
+                ensure_unique(&keychain, &sanitized, &entity).await?;
+                // the persona is shared by every key of the member
+                entity.set_name(&sanitized).await;
+                drop(keychain);
+                self.refresh_titles(&entity).await;
+                Some(format!("renamed {from} to {}", entity.name().await))
             }
             Command::Commit => {
-                let keychain = self.keychain.read().await;
-                let mut pubkeys = vec![];
-                for entity in keychain.iter() {
-                    let ent_str = entity.to_pubkey().await.to_string();
-                    pubkeys.push(ent_str);
-                }
-                let pubkeys = pubkeys.join("\n");
-                let mut tmpfile = self.args.authfile.clone();
-                tmpfile.push('~');
-                if let Err(e) = std::fs::write(&tmpfile, pubkeys) {
-                    log::error!(
-                        "failed to create temporary file to commit in-memory authorized keys: {e:#?}"
-                    );
-                    return Ok(());
-                };
-                if let Err(e) = std::fs::rename(tmpfile, &self.args.authfile) {
-                    log::error!(
-                        "failed to move temporary file to original authfile: {e:#?}: do we have write permissions to it?"
-                    );
-                    return Ok(());
-                };
+                let count = self.commit().await?;
+                Some(format!("committed {count} keys to {}", self.args.authfile))
             }
             Command::Info(entity_lookup) => {
-                let keychain = self.keychain.read().await;
-                let mut maybe_found_entity = None;
-                for entity in keychain.iter() {
-                    if entity_lookup.matches(entity).await {
-                        maybe_found_entity.replace(entity);
-                        break;
-                    }
-                }
                 // wow so much to query a user huh? anyways
-                let Some(entity) = maybe_found_entity else {
-                    return Ok(());
-                };
+                let entity = self.find(&entity_lookup).await?;
+                let mut keys = String::new();
+                let online = self.online_ids().await;
+                for key in self.identity_keys(&entity).await {
+                    let sessions = online.get(&key.key_data()).map_or(0, Vec::len);
+                    keys.push_str(&format!("  {} ({sessions} sessions)\n", key.fingerprint()));
+                }
 
                 let dossier = format!(
                     "
 name: {}
 role: {}
-fingerprint: {}
-
+keys:
+{keys}
 ",
                     entity.name().await,
                     entity.role().await,
-                    entity.fingerprint()
                 );
 
                 self.app.write().await.history.enqueue(Message::Dossier {
                     contents: dossier,
                     requested_by: self.id,
                 });
+                None
             }
             Command::Ban(entity_lookup) => {
-                let keychain = self.keychain.read().await;
-                let mut maybe_found_entity = None;
-                for entity in keychain.iter() {
-                    if entity_lookup.matches(entity).await {
-                        maybe_found_entity.replace(entity);
-                        break;
+                let entity = self.find(&entity_lookup).await?;
+                let current = self.entity().await;
+                if current.key_data() == entity.key_data() || current.same_identity(&entity) {
+                    // prevent user from banning themselves
+                    return Err(Error::NoBanSelf);
+                }
+
+                let keys = self.identity_keys(&entity).await;
+                let disconnected = self.remove_keys(&keys).await?;
+                Some(format!(
+                    "banned {} ({} keys), disconnected {disconnected} sessions",
+                    entity.name().await,
+                    keys.len()
+                ))
+            }
+            Command::Reload => {
+                self.reload().await?;
+                let count = self.keychain.read().await.len();
+                Some(format!("reloaded {count} keys from {}", self.args.authfile))
+            }
+            Command::Edit { target, text } => {
+                let fingerprint = self.entity().await.fingerprint();
+                let mut app = self.app.write().await;
+                let id = app.history.resolve(&target, &fingerprint)?;
+                let Some(Entry {
+                    message: Message::Chat(chat),
+                    ..
+                }) = app.history.get_mut(id)
+                else {
+                    return Err(Error::MessageNotFound(id));
+                };
+                if chat.deleted {
+                    return Err(Error::MessageNotFound(id));
+                }
+                if chat.fingerprint != fingerprint {
+                    return Err(Error::NotMessageAuthor(id));
+                }
+                chat.text = text;
+                chat.edited = true;
+                Some(format!("edited #{id}"))
+            }
+            Command::Delete(target) => {
+                let entity = self.entity().await;
+                let fingerprint = entity.fingerprint();
+                let is_admin = entity.role().await == entity::Role::Admin;
+                let mut app = self.app.write().await;
+                let id = app.history.resolve(&target, &fingerprint)?;
+                let Some(Entry {
+                    message: Message::Chat(chat),
+                    ..
+                }) = app.history.get_mut(id)
+                else {
+                    return Err(Error::MessageNotFound(id));
+                };
+                // admins may delete anyone's message
+                if chat.fingerprint != fingerprint && !is_admin {
+                    return Err(Error::NotMessageAuthor(id));
+                }
+                chat.text.clear();
+                chat.reactions.clear();
+                chat.deleted = true;
+                Some(format!("deleted #{id}"))
+            }
+            Command::Set(None) => {
+                let clients = self.clients.read().await;
+                let Some(client) = clients.get(&self.id) else {
+                    return Ok(None);
+                };
+                let contents = format!(
+                    "\n{}themes: {}\n",
+                    client.preferences,
+                    theme::names().join(", ")
+                );
+                drop(clients);
+                self.app.write().await.history.enqueue(Message::Dossier {
+                    contents,
+                    requested_by: self.id,
+                });
+                None
+            }
+            Command::Set(Some((key, value))) => {
+                let fingerprint = self.entity().await.fingerprint();
+                let mut clients = self.clients.write().await;
+                let Some(client) = clients.get_mut(&self.id) else {
+                    session_log!(self, warn, "failed to get handle on the current client");
+                    return Ok(None);
+                };
+                client.preferences.set(&key, &value)?;
+                client
+                    .preferences
+                    .save(Path::new(&self.args.data_dir), &fingerprint)?;
+                Some(format!("{key} set to {value}"))
+            }
+            Command::Audit(count) => {
+                let path = self.audit.read().await.path().to_path_buf();
+                let records = audit::read(&path)?;
+                let integrity = match audit::verify(&records) {
+                    Ok(()) if records.first().is_some_and(audit::Record::is_break) => {
+                        "chain intact since it was broken, see record #1".to_string()
+                    }
+                    Ok(()) => "chain intact".to_string(),
+                    Err(e) => e.to_string(),
+                };
+                let mut contents = format!("\naudit log ({integrity}):\n");
+                let skip = records.len().saturating_sub(count);
+                for record in records.iter().skip(skip) {
+                    contents.push_str(&format!("{record}\n"));
+                }
+                self.app.write().await.history.enqueue(Message::Dossier {
+                    contents,
+                    requested_by: self.id,
+                });
+                None
+            }
+            Command::Invite {
+                role,
+                uses,
+                expires_in,
+            } => {
+                let mut invites = self.invites.write().await;
+                let token = invites.create(role, uses, expires_in);
+                let contents = format!(
+                    "
+invite for {uses} {role} member(s), expiring in {} minutes ({} outstanding):
+  ssh -p {} {}{token}-<name>@<host>
+
+",
+                    expires_in.as_secs() / 60,
+                    invites.len(),
+                    self.port,
+                    invite::PREFIX,
+                );
+                drop(invites);
+                self.app.write().await.history.enqueue(Message::Dossier {
+                    contents,
+                    requested_by: self.id,
+                });
+                None
+            }
+            Command::Pending => {
+                let lobby = self.lobby.read().await;
+                if lobby.is_empty() {
+                    return Ok(Some("no keys are waiting in the lobby".to_string()));
+                }
+                let mut contents = String::from("\njoin requests:\n");
+                for request in lobby.iter() {
+                    let minutes = request.requested_at.elapsed().unwrap_or_default().as_secs() / 60;
+                    contents.push_str(&format!("  {} waiting {minutes}m", request.fingerprint));
+                    if let Some(name) = &request.name {
+                        contents.push_str(&format!(", invited as {name} ({})", request.role));
                     }
+                    contents.push('\n');
                 }
-                let Some(entity) = maybe_found_entity else {
-                    return Ok(());
+                drop(lobby);
+                self.app.write().await.history.enqueue(Message::Dossier {
+                    contents,
+                    requested_by: self.id,
+                });
+                None
+            }
+            Command::Approve { fingerprint, name } => {
+                let request = {
+                    let mut lobby = self.lobby.write().await;
+                    let request = lobby
+                        .get(&fingerprint)
+                        .ok_or_else(|| Error::NotPending(fingerprint.clone()))?;
+                    if name.is_none() && request.name.is_none() {
+                        return Err(Error::ApprovalNeedsName(fingerprint));
+                    }
+                    lobby
+                        .remove(&fingerprint)
+                        .ok_or(Error::NotPending(fingerprint.clone()))?
+                };
+                // the lobby is locked after the keychain, so the request is taken
+                // out while the key is added and put back should that fail
+                let name = name.or_else(|| request.name.clone()).unwrap_or_default();
+                let entity = Entity::new(request.key.clone(), &name, request.role);
+                let entity = match self.add_entity(entity).await {
+                    Ok(entity) => entity,
+                    Err(e) => {
+                        // holding the clients keeps sessions from leaving unnoticed
+                        let clients = self.clients.read().await;
+                        let mut request = request;
+                        request.ids.retain(|id| clients.contains_key(id));
+                        if !request.ids.is_empty() {
+                            self.lobby.write().await.put_back(request);
+                        }
+                        return Err(e);
+                    }
                 };
 
-                let key_data = entity.key_data();
-                if self.entity().await.key_data() == entity.key_data() {
-                    // prevent user from banning themselves
-                    return Err(Error::NoBanSelf);
+                let admitted = self.admit(&entity, &request.ids).await;
+                if self.args.commit_invites && request.name.is_some() {
+                    self.commit().await?;
                 }
+                Some(format!(
+                    "approved {} ({fingerprint}), let in {admitted} sessions",
+                    entity.name().await
+                ))
+            }
+            Command::Deny(fingerprint) => {
+                let request = self
+                    .lobby
+                    .write()
+                    .await
+                    .remove(&fingerprint)
+                    .ok_or_else(|| Error::NotPending(fingerprint.clone()))?;
+                let mut clients = self.clients.write().await;
+                let mut disconnected = 0;
+                for id in request.ids {
+                    let Some(client) = clients.get_mut(&id) else {
+                        continue;
+                    };
+                    self.see_off(id, client);
+                    if let Err(()) = client.handle.close(client.channel).await {
+                        return Err(Error::ClientDisconnectFailed(id));
+                    }
+                    clients.remove(&id);
+                    disconnected += 1;
+                }
+                Some(format!(
+                    "denied {fingerprint}, disconnected {disconnected} sessions"
+                ))
+            }
+            Command::AddKey(key) => {
+                let persona = self.entity().await.persona();
+                let entity = self.add_entity(Entity::with_persona(key, persona)).await?;
+                Some(format!(
+                    "added key {}, an admin must /commit to keep it",
+                    entity.fingerprint()
+                ))
+            }
+            Command::RemoveKey(fingerprint) => {
+                let current = self.entity().await;
+                if current.fingerprint() == fingerprint {
+                    return Err(Error::NoRemoveCurrentKey);
+                }
+                let Some(key) = self
+                    .identity_keys(&current)
+                    .await
+                    .into_iter()
+                    .find(|key| key.fingerprint() == fingerprint)
+                else {
+                    return Err(Error::NotYourKey(fingerprint));
+                };
+                let disconnected = self.remove_keys(&[key]).await?;
+                Some(format!(
+                    "removed key {fingerprint}, disconnected {disconnected} sessions"
+                ))
+            }
+            Command::Nick(name) => {
+                let entity = self.entity().await;
+                // checked and taken under one lock, so no one else claims the name in between
+                let keychain = self.keychain.write().await;
+                let persona = entity.persona();
+                let persona = persona.read().await;
+                let from = persona.name();
+                if persona.locked() {
+                    return Err(Error::NameLocked);
+                }
+                if let Some(nicked_at) = persona.nicked_at() {
+                    let elapsed = nicked_at.elapsed().unwrap_or_default();
+                    if elapsed < self.args.nick_cooldown {
+                        let remaining = self.args.nick_cooldown - elapsed;
+                        return Err(Error::NickCooldown(remaining.as_secs() + 1));
+                    }
+                }
+                drop(persona);
 
-                let mut key_data_to_user = self.key_data_to_user.write().await;
-                let mut key_data_pool = self.key_data_pool.write().await;
+                let to = sanitize::name(&name);
+                if to.is_empty() {
+                    return Err(Error::InvalidName(name));
+                }
+                if to == from {
+                    return Ok(Some(format!("your name is already {to}")));
+                }
+                ensure_unique(&keychain, &to, &entity).await?;
+                entity.nick(&to).await;
+                drop(keychain);
+
+                self.refresh_titles(&entity).await;
+                self.announce(message::Announcement::Renamed {
+                    from,
+                    to: to.clone(),
+                })
+                .await;
+                Some(format!("you are now known as {to}"))
+            }
+            Command::Lock { lookup, locked } => {
+                let entity = self.find(&lookup).await?;
+                entity.set_locked(locked).await;
+                let name = entity.name().await;
+                if locked {
+                    Some(format!("locked the name of {name}"))
+                } else {
+                    Some(format!("unlocked the name of {name}"))
+                }
+            }
+            Command::Blocked => {
+                let blocked = match self.blocklist.lock() {
+                    Ok(blocklist) => blocklist.blocked(std::time::Instant::now()),
+                    Err(e) => {
+                        session_log!(self, error, "failed to lock the blocklist: {e}");
+                        vec![]
+                    }
+                };
+                if blocked.is_empty() {
+                    return Ok(Some("no addresses are blocked".to_string()));
+                }
+                let mut contents = String::from("\nblocked addresses:\n");
+                for (address, remaining) in blocked {
+                    contents.push_str(&format!(
+                        "  {address} for {}m more\n",
+                        remaining.as_secs().div_ceil(60)
+                    ));
+                }
+                self.app.write().await.history.enqueue(Message::Dossier {
+                    contents,
+                    requested_by: self.id,
+                });
+                None
+            }
+            Command::Unblock(address) => {
+                let unblocked = match self.blocklist.lock() {
+                    Ok(mut blocklist) => blocklist.unblock(address),
+                    Err(e) => {
+                        session_log!(self, error, "failed to lock the blocklist: {e}");
+                        false
+                    }
+                };
+                if !unblocked {
+                    return Err(Error::NotBlocked(address));
+                }
+                Some(format!("unblocked {address}"))
+            }
+            Command::Shutdown { delay, reason } => {
+                let shutdown = shutdown::Shutdown {
+                    delay: delay.unwrap_or(self.args.shutdown_grace),
+                    reason,
+                    restart: false,
+                };
+                let notice = shutdown.notice();
+                let requested = self.shutdown.send_if_modified(|current| {
+                    if current.is_some() {
+                        return false;
+                    }
+                    *current = Some(shutdown);
+                    true
+                });
+                if !requested {
+                    return Err(Error::AlreadyShuttingDown);
+                }
+                session_log!(self, info, "shutdown requested");
+                Some(notice)
+            }
+            Command::Help(command) => {
+                let role = self.entity().await.role().await;
+                let contents = command::help(role, command.as_deref())?;
+                self.app.write().await.history.enqueue(Message::Dossier {
+                    contents,
+                    requested_by: self.id,
+                });
+                None
+            }
+            Command::React { target, emoji } => {
+                let entity = self.entity().await;
+                let mut app = self.app.write().await;
+                // "last" refers to the latest message in the chat, not the reactor's own
+                let id = match target {
+                    MessageTarget::Id(id) => id,
+                    MessageTarget::Last => {
+                        app.history.last_chat().ok_or(Error::NoPreviousMessage)?
+                    }
+                };
+                let Some(Entry {
+                    message: Message::Chat(chat),
+                    ..
+                }) = app.history.get_mut(id)
+                else {
+                    return Err(Error::MessageNotFound(id));
+                };
+                if chat.deleted {
+                    return Err(Error::MessageNotFound(id));
+                }
+                let reactor = reaction::Reactor {
+                    name: entity.name().await,
+                    fingerprint: entity.fingerprint(),
+                };
+                reaction::toggle(&mut chat.reactions, emoji, reactor);
+                None
+            }
+            Command::Reply { target, text } => {
+                let entity = self.entity().await;
+                let fingerprint = entity.fingerprint();
+                let mut app = self.app.write().await;
+                let parent = app.history.resolve(&target, &fingerprint)?;
+                if !app.history.contains(parent) {
+                    return Err(Error::MessageNotFound(parent));
+                }
+                if self.args.log_messages {
+                    session_log!(self, info, "reply sent to {parent}: {text:?}");
+                }
+                let chat = Chat::reply(entity.name().await, fingerprint, text, parent);
+                app.history.enqueue(Message::Chat(chat));
+                self.metrics.message_sent();
+                None
+            }
+            Command::Thread(target) => {
+                let fingerprint = self.entity().await.fingerprint();
+                let thread = match target {
+                    Some(target) => {
+                        let app = self.app.read().await;
+                        let id = app.history.resolve(&target, &fingerprint)?;
+                        if !app.history.contains(id) {
+                            return Err(Error::MessageNotFound(id));
+                        }
+                        Some(id)
+                    }
+                    None => None,
+                };
 
-                key_data_to_user.remove(&key_data);
-                key_data_pool.remove(&key_data);
+                let mut clients = self.clients.write().await;
+                let Some(client) = clients.get_mut(&self.id) else {
+                    session_log!(self, warn, "failed to get handle on the current client");
+                    return Ok(None);
+                };
+                client.thread = thread;
+                match thread {
+                    Some(id) => Some(format!("viewing thread of #{id}, send /thread to leave")),
+                    None => Some("viewing the full history".to_string()),
+                }
+            }
+        };
+        Ok(confirmation)
+    }
 
-                let mut key_data_to_id = self.key_data_to_id.write().await;
-                let Some(ids) = key_data_to_id.remove(&key_data) else {
+    async fn handle_message(&mut self) -> Result<(), Error> {
+        let handing_over = self.handing_over.clone();
+        let handing_over = handing_over.read().await;
+        let text = {
+            let mut clients = self.clients.write().await;
+            let Some(current_client) = clients.get_mut(&self.id) else {
+                session_log!(self, warn, "failed to get handle on the current client");
+                return Ok(());
+            };
+            // the text is kept to be sent again to the process taking over
+            if *handing_over {
+                current_client.statusline = Error::Restarting.to_string();
+                return Ok(());
+            }
+            let text = sanitize::text(&current_client.textarea.lines().join("\n"));
+            current_client.input_history.push(&text);
+            replace_text(&mut current_client.textarea, "");
+            text
+        };
+        let entity = self.entity().await;
+        let role = entity.role().await;
+        let name = entity.name().await;
+        let maybe_command = match Command::parse(&text, role, name.to_string()) {
+            Ok(c) => c,
+            Err(e) => {
+                // attempts at admin commands are worth knowing about
+                if let Error::NotAnAdmin(_) = e
+                    && let Some(spec) = text.split_whitespace().next().and_then(command::lookup)
+                {
+                    let action = spec.name.trim_start_matches('/');
+                    let target = text
+                        .split_whitespace()
+                        .skip(1)
+                        .collect::<Vec<_>>()
+                        .join(" ");
+                    let recorded = self.audit.write().await.append(
+                        &name,
+                        &entity.fingerprint(),
+                        action,
+                        &target,
+                        "denied: not an admin",
+                    );
+                    if let Err(e) = recorded {
+                        session_log!(
+                            self,
+                            error,
+                            "failed to record the denied {action} by {name} in the audit log: {}",
+                            error::describe(&e)
+                        );
+                    }
+                }
+                let mut clients = self.clients.write().await;
+                let Some(current_client) = clients.get_mut(&self.id) else {
+                    session_log!(self, warn, "failed to get handle on the current client");
                     return Ok(());
                 };
+                current_client.statusline = sanitize::line(&e.to_string());
+                return Ok(());
+            }
+        };
 
-                let mut clients = self.clients.write().await;
-                for id in ids {
-                    let Some(client) = clients.get(&id) else {
-                        continue;
+        let Some(mut command) = maybe_command else {
+            if self.args.log_messages {
+                session_log!(self, info, "message sent: {text:?}");
+            }
+            let chat = Chat::new(name, entity.fingerprint(), text);
+            self.app.write().await.history.enqueue(Message::Chat(chat));
+            self.metrics.message_sent();
+            self.render().await;
+            return Ok(());
+        };
+        if let Some(spec) = text.split_whitespace().next().and_then(command::lookup) {
+            self.metrics.command_executed(spec.name);
+        }
+        // the grace period applies to a shutdown without a delay and is audited as such
+        if let Command::Shutdown { delay, .. } = &mut command {
+            delay.get_or_insert(self.args.shutdown_grace);
+        }
+        let audit_entry = command.audit_entry();
+        let result = self.run_command(command).await;
+        if let Some((action, target)) = audit_entry {
+            let outcome = match &result {
+                Ok(_) => "ok".to_string(),
+                Err(e) => format!("failed: {}", error::describe(e)),
+            };
+            let recorded = self.audit.write().await.append(
+                &name,
+                &entity.fingerprint(),
+                action,
+                &target,
+                &outcome,
+            );
+            if let Err(e) = recorded {
+                session_log!(
+                    self,
+                    error,
+                    "failed to record {action} by {name} in the audit log: {}",
+                    error::describe(&e)
+                );
+            }
+        }
+        let mut clients = self.clients.write().await;
+        let Some(current_client) = clients.get_mut(&self.id) else {
+            session_log!(self, warn, "failed to get handle on the current client");
+            return Ok(());
+        };
+        // a stale message is cleared when the result is shown in the history instead
+        current_client.statusline = match result {
+            Ok(confirmation) => sanitize::line(&confirmation.unwrap_or_default()),
+            Err(e) => sanitize::line(&error::describe(&e)),
+        };
+        Ok(())
+    }
+
+    async fn handle_keys(&mut self, data: &[u8]) -> Result<(), Error> {
+        match data {
+            // Sending Ctrl+C ends the session and disconnects the client
+            [3] => {
+                // members are present as long as any of their sessions is
+                if self.online_sessions(&*self.entity().await).await <= 1 {
+                    self.announce(message::Announcement::Left).await;
+                }
+                self.render().await;
+                {
+                    let mut key_data_to_id = self.key_data_to_id.write().await;
+                    let mut id_to_user = self.id_to_user.write().await;
+
+                    let Some(entity) = id_to_user.get(&self.id) else {
+                        session_log!(self, warn, "could not look up the member of the session");
+                        return Err(russh::Error::Disconnect.into());
+                    };
+                    // other sessions with the same key stay connected
+                    if let Some(ids) = key_data_to_id.get_mut(&entity.key_data()) {
+                        ids.retain(|id| *id != self.id);
+                    }
+
+                    id_to_user.remove(&self.id);
+                    if let Some(mut leaving_client) = self.clients.write().await.remove(&self.id) {
+                        self.see_off(self.id, &mut leaving_client);
+                    }
+                }
+                return Err(russh::Error::Disconnect.into());
+            }
+            // Press Return to send a message
+            [13] => {
+                if let Err(error) = self.handle_message().await {
+                    session_log!(
+                        self,
+                        error,
+                        "failed to handle a message or command: {}",
+                        error::describe(&error)
+                    );
+                };
+                // re-render
+                self.render().await;
+            }
+            // Alt-Return for multiline
+            [27, 13] => {
+                {
+                    let mut clients = self.clients.write().await;
+                    let Some(client) = clients.get_mut(&self.id) else {
+                        session_log!(self, warn, "failed to get handle on the current client");
+                        return Ok(());
                     };
-                    if let Err(()) = client.handle.close(client.channel).await {
-                        return Err(Error::ClientDisconnectFailed(id));
+                    client.textarea.input(Event::Key(Key::Char('\n')));
+                }
+                self.render().await;
+            }
+            data if !data.is_empty() => {
+                let mut iterator = data.iter().map(|d| Ok(*d));
+                while let Some(Ok(first)) = iterator.next() {
+                    match ratatui::termion::event::parse_event(first, &mut iterator) {
+                        Ok(Event::Key(Key::Char('\t'))) => self.complete().await,
+                        Ok(keycode) => {
+                            let mut clients = self.clients.write().await;
+                            let Some(client) = clients.get_mut(&self.id) else {
+                                session_log!(
+                                    self,
+                                    warn,
+                                    "failed to get handle on the current client"
+                                );
+                                return Ok(());
+                            };
+                            let (row, _) = client.textarea.cursor();
+                            let last_row = client.textarea.lines().len() - 1;
+                            let recalled = match keycode {
+                                // recall sent messages only from the edges of the draft
+                                Event::Key(Key::Up) if row == 0 => {
+                                    let current = client.textarea.lines().join("\n");
+                                    client.input_history.older(&current).map(str::to_string)
+                                }
+                                Event::Key(Key::Down) if row == last_row => {
+                                    client.input_history.newer()
+                                }
+                                _ => None,
+                            };
+                            match recalled {
+                                Some(text) => replace_text(&mut client.textarea, &text),
+                                None => {
+                                    client.textarea.input(keycode);
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            // keystrokes are chat content, kept out of the log unless asked for
+                            if self.args.log_messages {
+                                session_log!(
+                                    self,
+                                    warn,
+                                    "failed to parse keyboard input {data:?}: {e}"
+                                );
+                            } else {
+                                session_log!(self, warn, "failed to parse keyboard input: {e}");
+                            }
+                        }
                     }
-                    clients.remove(&id);
                 }
+                self.render().await;
             }
-            Command::Reload => self.reload().await?,
+            _ => {}
         }
+
         Ok(())
     }
 
-    async fn handle_message(&mut self) -> Result<(), Error> {
-        let text = {
-            let mut clients = self.clients.write().await;
-            let Some(current_client) = clients.get_mut(&self.id) else {
-                log::warn!(
-                    "failed to get handle on the current client with id: {}",
-                    self.id
-                );
-                return Ok(());
+    /// Completes the word before the cursor with a command name, username or fingerprint.
+    async fn complete(&mut self) {
+        let line = {
+            let clients = self.clients.read().await;
+            let Some(client) = clients.get(&self.id) else {
+                session_log!(self, warn, "failed to get handle on the current client");
+                return;
             };
-            let text = current_client.textarea.lines().join("\n");
-
-            // HACK: Clear the textarea on send. Select all, delete.
-            current_client.textarea.select_all();
-            current_client
-                .textarea
-                .input(ratatui::termion::event::Event::Key(Key::Delete));
-            text
+            let (row, col) = client.textarea.cursor();
+            if row != 0 {
+                return;
+            }
+            let line: String = client.textarea.lines()[row].chars().take(col).collect();
+            line
         };
+        let words: Vec<&str> = line.split(char::is_whitespace).collect();
+        let word = words.last().copied().unwrap_or_default();
+
+        let mut names = vec![];
+        let mut fingerprints = vec![];
+        for entity in self.keychain.read().await.iter() {
+            let name = entity.name().await;
+            // members with several keys are listed once
+            if !names.contains(&name) {
+                names.push(name);
+            }
+            fingerprints.push(entity.fingerprint());
+        }
+
         let role = self.entity().await.role().await;
-        let name = self.entity().await.name().await;
-        let maybe_command = match Command::parse(&text, role, name.to_string()) {
-            Ok(c) => c,
-            Err(e) => {
-                let mut clients = self.clients.write().await;
-                let Some(current_client) = clients.get_mut(&self.id) else {
-                    log::warn!(
-                        "failed to get handle on the current client with id: {}",
-                        self.id
-                    );
-                    return Ok(());
-                };
-                current_client.statusline = e.to_string();
-                return Ok(());
+        let candidates: Vec<String> = match words[..] {
+            [command] if command.starts_with('/') => {
+                command::names(role).map(str::to_string).collect()
             }
+            [.., mention] if mention.starts_with('@') => {
+                names.iter().map(|name| format!("@{name}")).collect()
+            }
+            ["/info" | "/ban" | "/lock" | "/unlock", _] => {
+                names.into_iter().chain(fingerprints).collect()
+            }
+            ["/rename", _] => names,
+            ["/removekey", _] => self
+                .identity_keys(&*self.entity().await)
+                .await
+                .iter()
+                .map(|key| key.fingerprint())
+                .collect(),
+            ["/approve" | "/deny", _] => self
+                .lobby
+                .read()
+                .await
+                .iter()
+                .map(|request| request.fingerprint.clone())
+                .collect(),
+            _ => return,
         };
 
-        let Some(command) = maybe_command else {
-            let message = format!("[{name}]: {text}");
-            self.app
-                .write()
-                .await
-                .history
-                .enqueue(Message::Plain(message));
-            self.render().await;
-            return Ok(());
+        let mut clients = self.clients.write().await;
+        let Some(client) = clients.get_mut(&self.id) else {
+            return;
         };
-        if let Err(e) = self.run_command(command).await {
+        let completed = match input::complete(word, &candidates) {
+            input::Completion::None => return,
+            input::Completion::Unique(completed) => format!("{completed} "),
+            input::Completion::Partial { prefix, options } => {
+                client.statusline = sanitize::line(&options.join("  "));
+                prefix
+            }
+        };
+        for _ in word.chars() {
+            client.textarea.delete_char();
+        }
+        client.textarea.insert_str(completed);
+    }
+
+    /// Inserts a bracketed paste into the textarea as a single draft.
+    async fn handle_paste(&mut self, text: &str, truncated: bool) {
+        {
             let mut clients = self.clients.write().await;
-            let Some(current_client) = clients.get_mut(&self.id) else {
-                log::warn!(
-                    "failed to get handle on the current client with id: {}",
-                    self.id
-                );
-                return Ok(());
+            let Some(client) = clients.get_mut(&self.id) else {
+                session_log!(self, warn, "failed to get handle on the current client");
+                return;
             };
-            current_client.statusline = e.to_string();
-            return Ok(());
+            client.textarea.insert_str(sanitize::text(text));
+            if truncated {
+                client.statusline =
+                    format!("paste truncated to {} bytes", self.args.max_paste_size);
+            }
         }
-        Ok(())
+        self.render().await;
     }
 }
 
 impl Server for AppServer {
     type Handler = Self;
-    fn new_client(&mut self, _: Option<std::net::SocketAddr>) -> Self {
-        let s = self.clone();
+    fn new_client(&mut self, peer: Option<std::net::SocketAddr>) -> Self {
+        let mut s = self.clone();
+        s.peer = peer;
+        if let Some(peer) = peer {
+            match self.connections.lock() {
+                Ok(mut connections) => {
+                    s.throttled = !connections.allow(peer.ip(), std::time::Instant::now());
+                }
+                Err(e) => log::error!("failed to lock the connection rate limiter: {e}"),
+            }
+        }
         self.id += 1;
         s
     }
-    fn handle_session_error(&mut self, error: <Self::Handler as russh::server::Handler>::Error) {
-        log::error!("session error: {error:#?}");
-    }
 }
 
 impl Handler for AppServer {
@@ -443,11 +1905,32 @@ impl Handler for AppServer {
             })?;
 
             let mut textarea = TextArea::default();
-            let title = self.entity().await.title().await;
-            let surrounding_block = Block::bordered()
-                .border_type(BorderType::Rounded)
-                .title(title);
-            textarea.set_block(surrounding_block);
+            let entity = self.id_to_user.read().await.get(&self.id).cloned();
+            let (title, preferences, waiting) = match &entity {
+                Some(entity) => (
+                    entity.title().await,
+                    Preferences::load(Path::new(&self.args.data_dir), &entity.fingerprint()),
+                    None,
+                ),
+                None => {
+                    let lobby = self.lobby.read().await;
+                    let Some(fingerprint) = lobby.fingerprint_of(self.id) else {
+                        session_log!(
+                            self,
+                            warn,
+                            "the session is neither a member nor waiting in the lobby"
+                        );
+                        return Ok(false);
+                    };
+                    (
+                        WAITING_TITLE.to_string(),
+                        Preferences::default(),
+                        Some(fingerprint.to_string()),
+                    )
+                }
+            };
+            let capabilities = Capabilities::default();
+            textarea.set_block(ui::textarea_block(title, &capabilities));
 
             let client = Client {
                 textarea,
@@ -455,31 +1938,31 @@ impl Handler for AppServer {
                 handle,
                 terminal,
                 statusline: String::default(),
+                thread: None,
+                paste: paste::Paste::default(),
+                input_history: input::InputHistory::default(),
+                preferences,
+                capabilities,
+                environment: vec![],
+                waiting,
             };
 
             self.clients.write().await.insert(self.id, client);
+            if entity.is_none() {
+                self.render().await;
+                return Ok(true);
+            }
+        }
+        if self.online_sessions(&*self.entity().await).await <= 1 {
+            self.announce(message::Announcement::Joined).await;
         }
-        self.announce(message::Announcement::Joined).await;
         Ok(true)
     }
 
-    async fn auth_publickey(&mut self, _: &str, key: &PublicKey) -> Result<Auth, Self::Error> {
-        // Search for the key in our keychain
-        if let Some(entity) = self.key_data_to_user.read().await.get(key.key_data()) {
-            // freeze everything, again
-            let mut id_to_user = self.id_to_user.write().await;
-            let mut key_data_to_id = self.key_data_to_id.write().await;
-
-            id_to_user.insert(self.id, entity.clone());
-
-            key_data_to_id
-                .entry(key.key_data().clone())
-                .or_default()
-                .push(self.id);
-
-            return Ok(Auth::Accept);
-        }
-        Ok(Auth::reject())
+    async fn auth_publickey(&mut self, user: &str, key: &PublicKey) -> Result<Auth, Self::Error> {
+        let auth = self.authenticate(user, key).await;
+        self.metrics.authenticated(matches!(auth, Auth::Accept));
+        Ok(auth)
     }
 
     async fn data(
@@ -488,92 +1971,35 @@ impl Handler for AppServer {
         data: &[u8],
         _session: &mut Session,
     ) -> Result<(), Self::Error> {
-        match data {
-            // Sending Ctrl+C ends the session and disconnects the client
-            [3] => {
-                self.announce(message::Announcement::Left).await;
-                self.render().await;
-                {
-                    let mut key_data_to_id = self.key_data_to_id.write().await;
-                    let mut id_to_user = self.id_to_user.write().await;
-
-                    let Some(entity) = id_to_user.get(&self.id) else {
-                        log::warn!(
-                            "could not look up the entity corresponding to the current id: {}",
-                            self.id
-                        );
-                        return Err(russh::Error::Disconnect.into());
-                    };
-                    let stray_key_data = entity.key_data();
-                    key_data_to_id.remove(&stray_key_data);
-
-                    id_to_user.remove(&self.id);
-                    if let Some(mut leaving_client) = self.clients.write().await.remove(&self.id)
-                        && let Err(e) = leaving_client
-                            .terminal
-                            .draw(|f| f.render_widget(Clear, f.area()))
-                    {
-                        log::error!("failed to clear the screen of leaving client: {e:?}");
-                    };
+        let chunks = {
+            let mut clients = self.clients.write().await;
+            let Some(client) = clients.get_mut(&self.id) else {
+                session_log!(self, warn, "failed to get handle on the current client");
+                return Ok(());
+            };
+            if client.waiting.is_some() {
+                // sessions in the lobby may only leave with Ctrl+C
+                if !data.contains(&3) {
+                    return Ok(());
                 }
-                return Err(russh::Error::Disconnect.into());
-            }
-            // Press Return to send a message
-            [13] => {
-                if let Err(error) = self.handle_message().await {
-                    log::error!(
-                        "failed to handle message or potential command sent by client {}: {:?}",
-                        self.id,
-                        error
-                    );
-                };
-                // re-render
-                self.render().await;
-            }
-            // Alt-Return for multiline
-            [27, 13] => {
-                {
-                    let mut clients = self.clients.write().await;
-                    let Some(client) = clients.get_mut(&self.id) else {
-                        log::warn!(
-                            "failed to get handle on the current client with id: {}",
-                            self.id
-                        );
-                        return Ok(());
-                    };
-                    client.textarea.input(Event::Key(Key::Char('\n')));
+                if let Some(mut leaving_client) = clients.remove(&self.id) {
+                    self.see_off(self.id, &mut leaving_client);
                 }
-                self.render().await;
+                drop(clients);
+                self.lobby.write().await.leave(self.id);
+                return Err(russh::Error::Disconnect.into());
             }
-            data if !data.is_empty() => {
-                let mut iterator = data.iter().map(|d| Ok(*d));
-                loop {
-                    let Some(Ok(first)) = iterator.next() else {
-                        break;
-                    };
+            client.paste.feed(data, self.args.max_paste_size)
+        };
 
-                    match ratatui::termion::event::parse_event(first, &mut iterator) {
-                        Ok(keycode) => {
-                            let mut clients = self.clients.write().await;
-                            let Some(client) = clients.get_mut(&self.id) else {
-                                log::warn!(
-                                    "failed to get handle on the current client with id: {}",
-                                    self.id
-                                );
-                                return Ok(());
-                            };
-                            client.textarea.input(keycode);
-                        }
-                        Err(e) => {
-                            log::warn!("failed to parse keyboard input data: {data:?}: {e}");
-                        }
-                    }
+        for chunk in chunks {
+            match chunk {
+                paste::Chunk::Keys(keys) => self.handle_keys(&keys).await?,
+                paste::Chunk::Paste { text, truncated } => {
+                    self.handle_paste(&text, truncated).await
                 }
-                self.render().await;
             }
-            _ => {}
         }
-
         Ok(())
     }
 
@@ -581,7 +2007,7 @@ impl Handler for AppServer {
     async fn pty_request(
         &mut self,
         channel: ChannelId,
-        _: &str,
+        term: &str,
         col_width: u32,
         row_height: u32,
         _: u32,
@@ -595,24 +2021,31 @@ impl Handler for AppServer {
             width: col_width as u16,
             height: row_height as u16,
         };
+        let title = self.title().await;
 
         {
             let mut clients = self.clients.write().await;
             let Some(client) = clients.get_mut(&self.id) else {
-                log::warn!(
-                    "failed to get handle on the current client with id: {}",
-                    self.id
-                );
+                session_log!(self, warn, "failed to get handle on the current client");
                 return Ok(());
             };
             if let Err(error) = client.terminal.resize(rect) {
-                log::error!(
-                    "failed to respond to terminal resize for client {}: {:#?}",
-                    self.id,
-                    error
-                );
+                session_log!(self, error, "failed to respond to terminal resize: {error}");
             };
 
+            client.capabilities = Capabilities::from_term(term);
+            for (name, value) in client.environment.iter() {
+                client.capabilities.apply_env(name, value);
+            }
+            let block = ui::textarea_block(title, &client.capabilities);
+            client.textarea.set_block(block);
+
+            // pasted text is wrapped in markers so that it can be told apart from typing
+            let writer = client.terminal.backend_mut();
+            if let Err(error) = writer.write_all(paste::ENABLE).and_then(|_| writer.flush()) {
+                session_log!(self, error, "failed to enable bracketed paste: {error}");
+            }
+
             session.channel_success(channel)?;
         }
         self.render().await;
@@ -620,6 +2053,34 @@ impl Handler for AppServer {
         Ok(())
     }
 
+    /// The client sets an environment variable, of which only those
+    /// describing the terminal capabilities are taken into account.
+    async fn env_request(
+        &mut self,
+        channel: ChannelId,
+        name: &str,
+        value: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let title = self.title().await;
+        {
+            let mut clients = self.clients.write().await;
+            let Some(client) = clients.get_mut(&self.id) else {
+                session_log!(self, warn, "failed to get handle on the current client");
+                return Ok(());
+            };
+            client.capabilities.apply_env(name, value);
+            client
+                .environment
+                .push((name.to_string(), value.to_string()));
+            let block = ui::textarea_block(title, &client.capabilities);
+            client.textarea.set_block(block);
+            session.channel_success(channel)?;
+        }
+        self.render().await;
+        Ok(())
+    }
+
     /// The client's pseudo-terminal window size has changed.
     async fn window_change_request(
         &mut self,
@@ -640,10 +2101,7 @@ impl Handler for AppServer {
         {
             let mut clients = self.clients.write().await;
             let Some(client) = clients.get_mut(&self.id) else {
-                log::warn!(
-                    "failed to get handle on the current client with id: {}",
-                    self.id
-                );
+                session_log!(self, warn, "failed to get handle on the current client");
                 return Ok(());
             };
 
@@ -663,51 +2121,65 @@ impl Handler for AppServer {
 
 impl Drop for AppServer {
     fn drop(&mut self) {
+        if let Some(user) = &self.rejected {
+            self.record_failure(user);
+        }
         let id = self.id;
         let clients = self.clients.clone();
+        let lobby = self.lobby.clone();
         tokio::spawn(async move {
-            let mut clients = clients.write().await;
-            clients.remove(&id);
+            clients.write().await.remove(&id);
+            lobby.write().await.leave(id);
         });
     }
 }
 
-pub enum Command {
-    Add(Entity),
-    Rename { from: String, to: String },
-    Commit,
-    Info(lookup::EntityLookup),
-    Ban(lookup::EntityLookup),
-    Reload,
+/// Fails if the entity cannot join the keychain as a new key.
+async fn check_new_entity(
+    keychain: &[Arc<Entity>],
+    key_data_pool: &HashSet<KeyData>,
+    entity: &Entity,
+) -> Result<(), Error> {
+    let name = entity.name().await;
+    if name.is_empty() {
+        return Err(Error::UnnamedMember);
+    }
+    if key_data_pool.contains(&entity.key_data()) {
+        return Err(Error::KeyAlreadyAdded(entity.fingerprint()));
+    }
+    ensure_unique(keychain, &name, entity).await
 }
 
-impl Command {
-    fn parse(text: &str, role: entity::Role, name: String) -> Result<Option<Self>, Error> {
-        let split: Vec<&str> = text.split(char::is_whitespace).collect();
-        let is_admin = role == entity::Role::Admin;
-
-        Ok(Some(match &split[..] {
-            ["/info", payload] => Self::Info(payload.parse()?),
-            ["/add" | "/rename" | "/ban" | "/commit" | "/reload", ..] if !is_admin => {
-                return Err(Error::NotAnAdmin(name));
-            }
-            ["/add", payload] => Self::Add(payload.parse()?),
-            ["/ban", payload] => Self::Ban(payload.parse()?),
-            ["/commit"] => Self::Commit,
-            ["/reload"] => Self::Reload,
-            ["/rename", from, to] => Self::Rename {
-                to: to.to_string(),
-                from: from.to_string(),
-            },
-            [
-                "/info" | "/add" | "/rename" | "/ban" | "/commit" | "/reload",
-                ..,
-            ] => {
-                return Err(Error::CommandParse(text.to_string()));
-            }
-            _ => return Ok(None),
-        }))
+/// Fails if the name is taken by a member other than the one the entity belongs to.
+async fn ensure_unique(keychain: &[Arc<Entity>], name: &str, entity: &Entity) -> Result<(), Error> {
+    for member in keychain.iter() {
+        if !member.same_identity(entity) && sanitize::names_collide(&member.name().await, name) {
+            return Err(Error::NameTaken(name.to_string()));
+        }
     }
+    Ok(())
+}
+
+/// Clears the screen of a leaving client and restores its terminal settings.
+fn farewell(client: &mut Client) -> std::io::Result<()> {
+    let cleared = client
+        .terminal
+        .draw(|f| f.render_widget(Clear, f.area()))
+        .map(|_| ());
+    // bracketed paste is turned off even when the screen could not be cleared
+    let writer = client.terminal.backend_mut();
+    let disabled = writer
+        .write_all(paste::DISABLE)
+        .and_then(|_| writer.flush());
+    cleared.and(disabled)
+}
+
+/// Replaces the contents of the textarea, keeping its surrounding block.
+fn replace_text(textarea: &mut TextArea<'static>, text: &str) {
+    // HACK: Select all, delete.
+    textarea.select_all();
+    textarea.input(Event::Key(Key::Delete));
+    textarea.insert_str(text);
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -728,17 +2200,224 @@ struct Args {
     /// Interface on the host to listen on
     #[arg(long, default_value = "0.0.0.0")]
     host: String,
+
+    /// Directory to store member preferences and the audit log in
+    #[arg(long, default_value = "./data")]
+    data_dir: String,
+
+    /// The largest paste in bytes accepted into the textarea, the rest is cut off
+    #[arg(long, default_value = "16384")]
+    max_paste_size: usize,
+
+    /// Write the keychain to the Authfile whenever a newcomer joins by invite
+    #[arg(long)]
+    commit_invites: bool,
+
+    /// Let unknown keys wait in a lobby for an admin to approve them instead of rejecting them
+    #[arg(long)]
+    lobby: bool,
+
+    /// Hold newcomers joining by invite in the lobby until an admin approves them
+    #[arg(long)]
+    approve_invites: bool,
+
+    /// How long members wait between changes to their name with /nick, such as 90s, 10m or 1h
+    #[arg(long, default_value = "10m", value_parser = invite::parse_duration)]
+    nick_cooldown: std::time::Duration,
+
+    /// The number of sessions a single key may have open at once
+    #[arg(long, default_value = "3")]
+    max_sessions_per_key: usize,
+
+    /// What to do with a new session of a key that has reached its limit
+    #[arg(long, value_enum, default_value = "reject")]
+    session_policy: limits::SessionPolicy,
+
+    /// The number of sessions the server holds at once, including those in the lobby
+    #[arg(long, default_value = "256")]
+    max_clients: usize,
+
+    /// The number of connections accepted from a single IP address within a minute
+    #[arg(long, default_value = "10")]
+    max_connections_per_minute: usize,
+
+    /// The number of connections closed without being let in after which an address is locked out
+    #[arg(long, default_value = "5")]
+    max_auth_failures: usize,
+
+    /// How long an address is locked out for, such as 90s, 15m or 1h
+    #[arg(long, default_value = "15m", value_parser = invite::parse_duration)]
+    lockout: std::time::Duration,
+
+    /// The number of keys that may wait in the lobby at once
+    #[arg(long, default_value = "16")]
+    max_pending: usize,
+
+    /// How long members have to wrap up after SIGTERM or SIGINT before they are
+    /// disconnected, such as 30s or 5m
+    #[arg(long, default_value = "0s", value_parser = invite::parse_duration)]
+    shutdown_grace: std::time::Duration,
+
+    /// Write the in-memory keychain to the Authfile when shutting down
+    #[arg(long)]
+    commit_on_shutdown: bool,
+
+    /// The level of detail of the log, overriding RUST_LOG for every module
+    #[arg(long)]
+    log_level: Option<log::LevelFilter>,
+
+    /// How log records are formatted
+    #[arg(long, value_enum, default_value = "text")]
+    log_format: logging::LogFormat,
+
+    /// Include the text of sent messages and keystrokes in the log
+    #[arg(long)]
+    log_messages: bool,
+
+    /// Serve Prometheus metrics over HTTP on this address, such as 127.0.0.1:9100
+    #[arg(long)]
+    metrics_addr: Option<std::net::SocketAddr>,
+
+    #[command(subcommand)]
+    command: Option<Subcommand>,
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+enum Subcommand {
+    /// Show the latest records of the audit log and check that it has not been tampered with
+    Audit {
+        /// The number of records to show
+        #[arg(default_value = "20")]
+        count: usize,
+    },
+}
+
+fn audit_log_path(args: &Args) -> std::path::PathBuf {
+    Path::new(&args.data_dir).join("audit.log")
+}
+
+/// Prints the audit log for the `audit` subcommand, failing if its chain is broken.
+fn print_audit(args: &Args, count: usize) -> Result<()> {
+    let path = audit_log_path(args);
+    if !path.exists() {
+        println!("no administrative actions have been recorded yet");
+        return Ok(());
+    }
+    let records = audit::read(&path)?;
+    let skip = records.len().saturating_sub(count);
+    for record in records.iter().skip(skip) {
+        println!("{record}");
+    }
+    audit::verify(&records)?;
+    if records.first().is_some_and(audit::Record::is_break) {
+        eprintln!("the chain was broken before record #1, which starts a new one");
+    }
+    Ok(())
+}
+
+/// Gathers the state handed over to the process taking over on restart.
+async fn save_state(
+    keychain: &Atomic<Vec<Arc<Entity>>>,
+    app: &Atomic<App>,
+    invites: &Atomic<invite::Invites>,
+    blocklist: &std::sync::Mutex<limits::Blocklist>,
+) -> restart::State {
+    let mut lines = vec![];
+    let mut personas = vec![];
+    for entity in keychain.read().await.iter() {
+        lines.push(entity.to_pubkey().await.to_string());
+        let persona = entity.persona();
+        let persona = persona.read().await;
+        personas.push(restart::SavedPersona {
+            fingerprint: entity.fingerprint(),
+            nicked_at: persona.nicked_at(),
+            locked: persona.locked(),
+        });
+    }
+    let (history, next_id) = restart::save_history(&app.read().await.history).await;
+    let blocked = match blocklist.lock() {
+        Ok(blocklist) => blocklist.blocked(std::time::Instant::now()),
+        Err(e) => {
+            log::error!("failed to lock the blocklist: {e}");
+            vec![]
+        }
+    };
+    restart::State {
+        keychain: lines.join("\n"),
+        history,
+        next_id,
+        blocked,
+        personas,
+        invites: invites.read().await.clone(),
+    }
+}
+
+/// Samples the server state exported along with the metrics.
+async fn sample_gauges(
+    clients: &Atomic<HashMap<usize, Client>>,
+    id_to_user: &Atomic<HashMap<usize, Arc<Entity>>>,
+    app: &Atomic<App>,
+) -> metrics::Gauges {
+    let mut gauges = metrics::Gauges::default();
+    // roles are looked up before the clients are locked, as when a client leaves
+    let mut roles = HashMap::new();
+    for (id, entity) in id_to_user.read().await.iter() {
+        roles.insert(*id, entity.role().await.to_string());
+    }
+    {
+        let clients = clients.read().await;
+        gauges.clients = clients.len();
+        for (id, client) in clients.iter() {
+            let role = match roles.remove(id) {
+                Some(role) if client.waiting.is_none() => role,
+                _ => "waiting".to_string(),
+            };
+            *gauges.sessions.entry(role).or_default() += 1;
+        }
+    }
+    let app = app.read().await;
+    gauges.history_len = app.history.len();
+    gauges.history_capacity = app.history.capacity();
+    gauges
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::builder()
-        .filter_level(log::LevelFilter::Debug)
-        .init();
-
     let args = Args::parse();
+    logging::init(args.log_level, args.log_format);
+    if let Some(Subcommand::Audit { count }) = args.command {
+        return print_audit(&args, count);
+    }
+
+    // a process taking over from a restarted one carries on with its state
+    let mut restored = restart::take_state()?;
+    let listener = if let Some(listener) = restart::inherited_listener()? {
+        log::info!("took over the listener from the previous process");
+        tokio::net::TcpListener::from_std(listener)?
+    } else if let Some(listener) = systemd::listener()? {
+        log::info!("listening on the socket passed by systemd");
+        tokio::net::TcpListener::from_std(listener)?
+    } else {
+        tokio::net::TcpListener::bind((args.host.clone(), args.port)).await?
+    };
+    let port = listener.local_addr()?.port();
 
-    let keychain = authfile::read(Path::new(&args.authfile)).await?;
+    let keychain = match &restored {
+        Some(state) => authfile::parse(state.keychain.as_bytes()).await?,
+        None => authfile::read(Path::new(&args.authfile)).await?,
+    };
+    if let Some(state) = &restored {
+        let personas: HashMap<_, _> = state
+            .personas
+            .iter()
+            .map(|persona| (persona.fingerprint.as_str(), persona))
+            .collect();
+        for entity in keychain.entities.iter() {
+            if let Some(persona) = personas.get(entity.fingerprint().as_str()) {
+                entity.restore(persona.nicked_at, persona.locked).await;
+            }
+        }
+    }
     let key_data_pool = new_atomic(keychain.key_pool);
     let key_data_to_id = new_atomic(HashMap::new());
     let id_to_user = new_atomic(HashMap::new());
@@ -752,13 +2431,83 @@ async fn main() -> Result<()> {
     let key_data_to_user = new_atomic(raw_key_data_to_user);
     let keychain = new_atomic(keychain.entities);
 
-    let app = App {
-        history: AllocRingBuffer::new(args.history_size),
+    let history = match &mut restored {
+        Some(state) => {
+            let entries = std::mem::take(&mut state.history);
+            restart::restore_history(args.history_size, entries, state.next_id)
+        }
+        None => History::new(args.history_size),
     };
+    let app = App { history };
 
     let app = new_atomic(app);
+    let (audit, broken) = audit::AuditLog::open(&audit_log_path(&args))?;
+    if let Some(e) = broken {
+        log::error!(
+            "the audit log chain is broken, recording the break and starting a new chain: {}",
+            error::describe(&e)
+        );
+    }
+    let audit = new_atomic(audit);
+
+    let invites = match &mut restored {
+        Some(state) => std::mem::take(&mut state.invites),
+        None => invite::Invites::default(),
+    };
 
+    let connections = limits::RateLimiter::new(
+        args.max_connections_per_minute,
+        std::time::Duration::from_secs(60),
+    );
+    let mut blocklist = limits::Blocklist::new(args.max_auth_failures, args.lockout);
+    if let Some(state) = &restored {
+        let now = std::time::Instant::now();
+        for (ip, remaining) in state.blocked.iter() {
+            blocklist.block(*ip, now + *remaining);
+        }
+    }
+    let metrics = Arc::new(metrics::Metrics::default());
+    if let Some(addr) = args.metrics_addr {
+        // the previous process holds on to the address until its sessions are closed
+        let listener = match restored {
+            Some(_) => None,
+            None => Some(tokio::net::TcpListener::bind(addr).await?),
+        };
+        let (clients, id_to_user, app) = (clients.clone(), id_to_user.clone(), app.clone());
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            let listener = match listener {
+                Some(listener) => listener,
+                None => match metrics::bind_when_free(addr).await {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        log::error!("failed to serve metrics on {addr}: {e}");
+                        return;
+                    }
+                },
+            };
+            log::info!("serving metrics on http://{addr}/metrics");
+            metrics::serve(listener, metrics, move || {
+                let (clients, id_to_user, app) = (clients.clone(), id_to_user.clone(), app.clone());
+                async move { sample_gauges(&clients, &id_to_user, &app).await }
+            })
+            .await
+        });
+    }
+    if let Some(interval) = systemd::watchdog_interval() {
+        let (clients, app) = (clients.clone(), app.clone());
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                // a server stuck holding its state stops pinging and gets restarted
+                drop(clients.read().await);
+                drop(app.read().await);
+                systemd::notify("WATCHDOG=1");
+            }
+        });
+    }
     let mut sh = AppServer {
+        handing_over: new_atomic(false),
         app,
         keychain,
         id_to_user,
@@ -767,8 +2516,20 @@ async fn main() -> Result<()> {
         key_data_to_user,
         clients,
         args,
+        port,
+        audit,
+        invites: new_atomic(invites),
+        lobby: new_atomic(lobby::Lobby::default()),
+        connections: Arc::new(std::sync::Mutex::new(connections)),
+        blocklist: Arc::new(std::sync::Mutex::new(blocklist)),
+        metrics,
+        shutdown: Arc::new(tokio::sync::watch::channel(None).0),
+        peer: None,
+        fingerprint: None,
+        throttled: false,
+        rejected: None,
         id: 0,
     };
-    sh.run().await?;
+    sh.run(listener).await?;
     Ok(())
 }
@@ -0,0 +1,178 @@
+use crate::theme::Theme;
+use ratatui::style::{Modifier, Style};
+use ratatui::text::{Line, Span};
+
+const FENCE: &str = "```";
+const URL_SCHEMES: [&str; 2] = ["https://", "http://"];
+
+/// Whether the text opens with a fenced code block, in which case
+/// the block should start on a line of its own.
+pub fn starts_with_fence(text: &str) -> bool {
+    text.trim_start().starts_with(FENCE)
+}
+
+/// Parses a small subset of markdown into styled lines:
+/// `*bold*`, `_italic_`, `` `code` ``, fenced code blocks and URLs.
+/// Anything that does not parse is shown literally.
+pub fn lines(text: &str, theme: &Theme) -> Vec<Line<'static>> {
+    let mut lines = vec![];
+    let mut in_code_block = false;
+    for line in text.lines() {
+        if line.trim_start().starts_with(FENCE) {
+            in_code_block = !in_code_block;
+            continue;
+        }
+        if in_code_block {
+            lines.push(Line::styled(line.to_string(), theme.code));
+        } else {
+            lines.push(inline(line, theme));
+        }
+    }
+    lines
+}
+
+fn inline(line: &str, theme: &Theme) -> Line<'static> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut spans = vec![];
+    let mut plain = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let previous = i.checked_sub(1).map(|p| chars[p]);
+
+        let styled = match c {
+            '`' => closing(&chars, i, '`', false).map(|end| (end, theme.code)),
+            '*' | '_' if previous.is_none_or(|p| !p.is_alphanumeric()) => {
+                let modifier = if c == '*' {
+                    Modifier::BOLD
+                } else {
+                    Modifier::ITALIC
+                };
+                closing(&chars, i, c, true)
+                    .map(|end| (end, Style::default().add_modifier(modifier)))
+            }
+            _ => None,
+        };
+        if let Some((end, style)) = styled {
+            flush(&mut spans, &mut plain);
+            let inner: String = chars[i + 1..end].iter().collect();
+            spans.push(Span::styled(inner, style));
+            i = end + 1;
+            continue;
+        }
+
+        if previous.is_none_or(|p| p.is_whitespace() || p == '(')
+            && let Some(len) = url_length(&chars[i..])
+        {
+            flush(&mut spans, &mut plain);
+            let url: String = chars[i..i + len].iter().collect();
+            spans.push(Span::styled(
+                url,
+                Style::default().add_modifier(Modifier::UNDERLINED),
+            ));
+            i += len;
+            continue;
+        }
+
+        plain.push(c);
+        i += 1;
+    }
+    flush(&mut spans, &mut plain);
+    Line::from(spans)
+}
+
+fn flush(spans: &mut Vec<Span<'static>>, plain: &mut String) {
+    if !plain.is_empty() {
+        spans.push(Span::raw(std::mem::take(plain)));
+    }
+}
+
+/// Finds the index of the delimiter closing the one at `open`.
+/// Emphasis must hug its content and not sit inside a word, so that
+/// `snake_case_names` and `2 * 3 * 4` stay literal.
+fn closing(chars: &[char], open: usize, delimiter: char, emphasis: bool) -> Option<usize> {
+    let first = *chars.get(open + 1)?;
+    if emphasis && first.is_whitespace() {
+        return None;
+    }
+    (open + 2..chars.len()).find(|&end| {
+        if chars[end] != delimiter {
+            return false;
+        }
+        if !emphasis {
+            return true;
+        }
+        let hugs = !chars[end - 1].is_whitespace();
+        let word_ends = chars.get(end + 1).is_none_or(|n| !n.is_alphanumeric());
+        hugs && word_ends
+    })
+}
+
+/// The number of characters of the URL at the start of the slice, if any.
+fn url_length(chars: &[char]) -> Option<usize> {
+    let start: String = chars.iter().take(8).collect();
+    let scheme = URL_SCHEMES.iter().find(|s| start.starts_with(*s))?;
+    let mut len = chars.iter().take_while(|c| !c.is_whitespace()).count();
+    // trailing punctuation most likely belongs to the sentence
+    while len > 0 && ".,;:!?)'\"".contains(chars[len - 1]) {
+        len -= 1;
+    }
+    (len > scheme.len()).then_some(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::theme::THEMES;
+
+    fn inline(line: &str) -> Line<'static> {
+        super::inline(line, &THEMES[0])
+    }
+
+    fn contents(line: &Line) -> Vec<String> {
+        line.spans.iter().map(|s| s.content.to_string()).collect()
+    }
+
+    #[test]
+    fn test_inline_styles() {
+        let line = inline("a *bold* and _italic_ `co*de*`");
+        assert_eq!(
+            contents(&line),
+            ["a ", "bold", " and ", "italic", " ", "co*de*"]
+        );
+        assert!(line.spans[1].style.add_modifier.contains(Modifier::BOLD));
+        assert!(line.spans[3].style.add_modifier.contains(Modifier::ITALIC));
+        assert_eq!(line.spans[5].style, THEMES[0].code);
+    }
+
+    #[test]
+    fn test_literal_delimiters() {
+        for text in ["snake_case_name", "2 * 3 * 4", "a lone `tick", "*"] {
+            assert_eq!(contents(&inline(text)), [text]);
+        }
+    }
+
+    #[test]
+    fn test_urls() {
+        let line = inline("see (https://example.com/a_b_c).");
+        assert_eq!(
+            contents(&line),
+            ["see (", "https://example.com/a_b_c", ")."]
+        );
+        assert!(
+            line.spans[1]
+                .style
+                .add_modifier
+                .contains(Modifier::UNDERLINED)
+        );
+    }
+
+    #[test]
+    fn test_fenced_code_block() {
+        let lines = lines("look:\n```\n  *not bold*\n```\ndone", &THEMES[0]);
+        assert_eq!(lines.len(), 3);
+        assert_eq!(contents(&lines[1]), ["  *not bold*"]);
+        assert_eq!(lines[1].style, THEMES[0].code);
+    }
+}
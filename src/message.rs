@@ -1,12 +1,19 @@
-use crate::entity::ArcPersona;
-use ratatui::style::Color;
+use crate::capabilities::Capabilities;
+use crate::entity::{ArcPersona, Persona};
+use crate::history::{Entry, MessageId};
+use crate::markup;
+use crate::reaction::Reaction;
+use crate::theme::Theme;
+use ratatui::style::Modifier;
 use ratatui::style::Style;
-use ratatui::text::Text;
+use ratatui::text::{Line, Span, Text};
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub enum Announcement {
     Joined,
     Left,
+    Renamed { from: String, to: String },
 }
 
 #[derive(Clone)]
@@ -15,36 +22,176 @@ pub(crate) enum Message {
         action: Announcement,
         persona: ArcPersona,
     },
-    Plain(String),
+    Chat(Chat),
     Dossier {
         contents: String,
         requested_by: usize,
     },
+    /// A notice from the server to everyone, such as an upcoming shutdown
+    Notice(String),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct Chat {
+    pub author: String,
+    // Fingerprint of the key the message was sent with, used to check ownership
+    pub fingerprint: String,
+    pub text: String,
+    // The message this one is a reply to
+    pub parent: Option<MessageId>,
+    pub reactions: Vec<Reaction>,
+    pub edited: bool,
+    pub deleted: bool,
+}
+
+// The longest a quoted parent message can be before it is truncated
+const QUOTE_WIDTH: usize = 48;
+
+impl Chat {
+    pub fn new(author: String, fingerprint: String, text: String) -> Self {
+        Self {
+            author,
+            fingerprint,
+            text,
+            parent: None,
+            reactions: vec![],
+            edited: false,
+            deleted: false,
+        }
+    }
+
+    pub fn reply(author: String, fingerprint: String, text: String, parent: MessageId) -> Self {
+        Self {
+            parent: Some(parent),
+            ..Self::new(author, fingerprint, text)
+        }
+    }
+
+    /// A single line summary of the message to show above its replies.
+    fn quote(&self, ellipsis: &str) -> String {
+        if self.deleted {
+            return "[deleted]".to_string();
+        }
+        let first_line = self.text.lines().next().unwrap_or_default();
+        let mut quote: String = first_line.chars().take(QUOTE_WIDTH).collect();
+        if quote.len() < first_line.len() || self.text.lines().nth(1).is_some() {
+            quote.push_str(ellipsis);
+        }
+        quote
+    }
+
+    fn text_content(
+        &self,
+        id: MessageId,
+        parent: Option<&Entry>,
+        theme: &Theme,
+        capabilities: &Capabilities,
+    ) -> Text<'_> {
+        let dim = theme.dim;
+        let quote = self.parent.map(|parent_id| {
+            let summary = match parent {
+                Some(Entry {
+                    message: Message::Chat(parent),
+                    ..
+                }) => format!(
+                    "[{}]: {}",
+                    parent.author,
+                    parent.quote(capabilities.ellipsis())
+                ),
+                _ => "(no longer in history)".to_string(),
+            };
+            let marker = capabilities.quote_marker();
+            Line::styled(format!("{marker} #{parent_id} {summary}"), dim)
+        });
+
+        let prefix = vec![
+            Span::styled(format!("#{id} "), dim),
+            Span::raw(format!("[{}]: ", self.author)),
+        ];
+
+        if self.deleted {
+            let mut spans = prefix;
+            spans.push(Span::styled(
+                "[deleted]",
+                dim.add_modifier(Modifier::ITALIC),
+            ));
+            return Text::from_iter(quote.into_iter().chain([Line::from(spans)]));
+        }
+
+        let mut lines = markup::lines(&self.text, theme);
+        if lines.is_empty() || markup::starts_with_fence(&self.text) {
+            // keep the author on a line of their own above a code block
+            lines.insert(0, Line::default());
+        }
+        let mut first = prefix;
+        first.append(&mut lines[0].spans);
+        lines[0] = Line::from(first);
+
+        if self.edited
+            && let Some(last) = lines.last_mut()
+        {
+            last.push_span(Span::styled(" (edited)", dim));
+        }
+        if !self.reactions.is_empty() {
+            lines.push(self.reaction_line(dim));
+        }
+        Text::from_iter(quote.into_iter().chain(lines))
+    }
+
+    /// Aggregates the reactions into a single line shown beneath the message,
+    /// such as `🎉 2 (bob@work, h@cafe)`.
+    fn reaction_line(&self, dim: Style) -> Line<'_> {
+        let mut spans = vec![Span::raw("  ")];
+        for reaction in self.reactions.iter() {
+            let names: Vec<&str> = reaction.reactors.iter().map(|r| r.name.as_str()).collect();
+            spans.push(Span::raw(format!(
+                "{} {} ",
+                reaction.emoji.as_str(),
+                reaction.reactors.len()
+            )));
+            spans.push(Span::styled(format!("({})  ", names.join(", ")), dim));
+        }
+        Line::from(spans)
+    }
+}
+
+impl Announcement {
+    /// The announcement as shown in the history, naming the member as they are now.
+    pub fn describe(&self, persona: &Persona) -> String {
+        match self {
+            Announcement::Joined => format!(
+                "{} has joined the chat with {} privileges",
+                persona.name(),
+                persona.role()
+            ),
+            Announcement::Left => format!(
+                "{} with {} privileges has left the chat",
+                persona.name(),
+                persona.role()
+            ),
+            // the names at the time, as the persona may be renamed again
+            Announcement::Renamed { from, to } => format!("{from} is now known as {to}"),
+        }
+    }
 }
 
 impl Message {
-    pub async fn text_content(&self) -> Text<'_> {
+    /// Renders the message, quoting its parent entry if it is a reply.
+    pub async fn text_content(
+        &self,
+        id: MessageId,
+        parent: Option<&Entry>,
+        theme: &Theme,
+        capabilities: &Capabilities,
+    ) -> Text<'_> {
         match self {
             Message::Announce { action, persona } => {
-                let persona = persona.read().await;
-                let announcement = match action {
-                    Announcement::Joined => format!(
-                        "{} has joined the chat with {} privileges",
-                        persona.name(),
-                        persona.role()
-                    ),
-                    Announcement::Left => format!(
-                        "{} with {} privileges has left the chat",
-                        persona.name(),
-                        persona.role()
-                    ),
-                };
-                Text::styled(announcement, Style::default().fg(Color::Green))
-            }
-            Message::Dossier { contents, .. } => {
-                Text::styled(contents, Style::default().fg(Color::LightCyan))
+                let announcement = action.describe(&*persona.read().await);
+                Text::styled(announcement, theme.announcement)
             }
-            Message::Plain(s) => Text::raw(s),
+            Message::Dossier { contents, .. } => Text::styled(contents, theme.dossier),
+            Message::Notice(notice) => Text::styled(notice, theme.announcement),
+            Message::Chat(chat) => chat.text_content(id, parent, theme, capabilities),
         }
     }
 }
@@ -0,0 +1,297 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+// Upper bounds in seconds of the render duration histogram buckets
+const RENDER_BUCKETS: [f64; 9] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0];
+
+// How long to keep trying to bind an address still held by the previous process
+const BIND_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The largest request head read before answering
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Values sampled from the server state when the metrics are scraped.
+#[derive(Default)]
+pub struct Gauges {
+    pub clients: usize,
+    /// Sessions by the role of their member, or "waiting" for those in the lobby
+    pub sessions: BTreeMap<String, usize>,
+    pub history_len: usize,
+    pub history_capacity: usize,
+}
+
+/// Counters updated as the server runs.
+#[derive(Default)]
+pub struct Metrics {
+    messages_sent: AtomicU64,
+    auth_accepted: AtomicU64,
+    auth_rejected: AtomicU64,
+    commands: Mutex<BTreeMap<&'static str, u64>>,
+    render_buckets: [AtomicU64; RENDER_BUCKETS.len()],
+    render_count: AtomicU64,
+    render_micros: AtomicU64,
+}
+
+impl Metrics {
+    pub fn message_sent(&self) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn authenticated(&self, accepted: bool) {
+        let counter = if accepted {
+            &self.auth_accepted
+        } else {
+            &self.auth_rejected
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn command_executed(&self, name: &'static str) {
+        match self.commands.lock() {
+            Ok(mut commands) => *commands.entry(name).or_default() += 1,
+            Err(e) => log::error!("failed to lock the command counters: {e}"),
+        }
+    }
+
+    pub fn rendered(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        // buckets are cumulative when exported, each observation is counted once here
+        if let Some(bucket) = RENDER_BUCKETS.iter().position(|le| seconds <= *le) {
+            self.render_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.render_count.fetch_add(1, Ordering::Relaxed);
+        self.render_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Formats the metrics in the Prometheus text exposition format.
+    pub fn export(&self, gauges: &Gauges) -> String {
+        let mut out = String::new();
+        let mut family = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+            let _ = writeln!(out, "# HELP publicly_{name} {help}");
+            let _ = writeln!(out, "# TYPE publicly_{name} {kind}");
+            for (suffix, value) in samples {
+                let _ = writeln!(out, "publicly_{name}{suffix} {value}");
+            }
+        };
+
+        family(
+            "connected_clients",
+            "gauge",
+            "Sessions connected, including those waiting in the lobby.",
+            vec![(String::new(), gauges.clients.to_string())],
+        );
+        family(
+            "sessions",
+            "gauge",
+            "Sessions connected by role.",
+            gauges
+                .sessions
+                .iter()
+                .map(|(role, count)| (format!("{{role=\"{role}\"}}"), count.to_string()))
+                .collect(),
+        );
+        family(
+            "history_messages",
+            "gauge",
+            "Messages held in the chat history.",
+            vec![(String::new(), gauges.history_len.to_string())],
+        );
+        family(
+            "history_capacity",
+            "gauge",
+            "Messages the chat history holds before the oldest disappears.",
+            vec![(String::new(), gauges.history_capacity.to_string())],
+        );
+        family(
+            "messages_sent_total",
+            "counter",
+            "Chat messages sent.",
+            vec![(
+                String::new(),
+                self.messages_sent.load(Ordering::Relaxed).to_string(),
+            )],
+        );
+        let commands = match self.commands.lock() {
+            Ok(commands) => commands
+                .iter()
+                .map(|(name, count)| {
+                    let name = name.trim_start_matches('/');
+                    (format!("{{command=\"{name}\"}}"), count.to_string())
+                })
+                .collect(),
+            Err(_) => vec![],
+        };
+        family(
+            "commands_total",
+            "counter",
+            "Commands executed by name.",
+            commands,
+        );
+        family(
+            "auth_total",
+            "counter",
+            "Public key authentications by result.",
+            vec![
+                (
+                    "{result=\"accepted\"}".to_string(),
+                    self.auth_accepted.load(Ordering::Relaxed).to_string(),
+                ),
+                (
+                    "{result=\"rejected\"}".to_string(),
+                    self.auth_rejected.load(Ordering::Relaxed).to_string(),
+                ),
+            ],
+        );
+
+        let mut histogram = vec![];
+        let mut cumulative = 0;
+        for (le, bucket) in RENDER_BUCKETS.iter().zip(self.render_buckets.iter()) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            histogram.push((format!("_bucket{{le=\"{le}\"}}"), cumulative.to_string()));
+        }
+        let count = self.render_count.load(Ordering::Relaxed);
+        let sum = self.render_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        histogram.push(("_bucket{le=\"+Inf\"}".to_string(), count.to_string()));
+        histogram.push(("_sum".to_string(), sum.to_string()));
+        histogram.push(("_count".to_string(), count.to_string()));
+        family(
+            "render_duration_seconds",
+            "histogram",
+            "Time taken to render the chat for every client.",
+            histogram,
+        );
+        out
+    }
+}
+
+/// Binds the address once it is free, as it is still held for a moment
+/// by the process being replaced on restart.
+pub async fn bind_when_free(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let deadline = tokio::time::Instant::now() + BIND_TIMEOUT;
+    loop {
+        match TcpListener::bind(addr).await {
+            Err(e)
+                if e.kind() == std::io::ErrorKind::AddrInUse
+                    && tokio::time::Instant::now() < deadline =>
+            {
+                tokio::time::sleep(Duration::from_millis(100)).await
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Answers `GET /metrics` on the listener until the server stops.
+/// `gauges` samples the server state for every scrape.
+pub async fn serve<F, Fut>(listener: TcpListener, metrics: Arc<Metrics>, gauges: F)
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Gauges> + Send,
+{
+    let gauges = Arc::new(gauges);
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                log::error!("failed to accept a metrics connection: {e}");
+                continue;
+            }
+        };
+        // a slow client holds up only its own connection
+        let (metrics, gauges) = (metrics.clone(), gauges.clone());
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &metrics, &*gauges).await {
+                log::warn!("failed to answer the metrics request from {peer}: {e}");
+            }
+        });
+    }
+}
+
+async fn respond<F, Fut>(
+    mut stream: TcpStream,
+    metrics: &Metrics,
+    gauges: &F,
+) -> std::io::Result<()>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Gauges>,
+{
+    let Ok(request) = tokio::time::timeout(Duration::from_secs(5), read_head(&mut stream)).await
+    else {
+        return Ok(());
+    };
+    let request = request?;
+
+    // the server state is only sampled for actual scrapes
+    let response = if request.starts_with(b"GET /metrics ") {
+        let body = metrics.export(&gauges().await);
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Reads the request line and headers, up to a limit.
+async fn read_head(stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+    let mut request = Vec::with_capacity(1024);
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let n = stream.read(&mut buffer).await?;
+        if n == 0 || request.len() + n > MAX_REQUEST_SIZE {
+            break;
+        }
+        request.extend_from_slice(&buffer[..n]);
+    }
+    Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_export() {
+        let metrics = Metrics::default();
+        metrics.message_sent();
+        metrics.command_executed("/ban");
+        metrics.command_executed("/ban");
+        metrics.authenticated(false);
+        metrics.rendered(Duration::from_millis(3));
+        metrics.rendered(Duration::from_secs(2));
+
+        let gauges = Gauges {
+            clients: 2,
+            sessions: BTreeMap::from([("admin".to_string(), 1), ("waiting".to_string(), 1)]),
+            history_len: 5,
+            history_capacity: 128,
+        };
+        let exported = metrics.export(&gauges);
+        for line in [
+            "# TYPE publicly_sessions gauge",
+            "publicly_connected_clients 2",
+            "publicly_sessions{role=\"waiting\"} 1",
+            "publicly_messages_sent_total 1",
+            "publicly_commands_total{command=\"ban\"} 2",
+            "publicly_auth_total{result=\"rejected\"} 1",
+            "publicly_render_duration_seconds_bucket{le=\"0.0025\"} 0",
+            "publicly_render_duration_seconds_bucket{le=\"0.005\"} 1",
+            "publicly_render_duration_seconds_bucket{le=\"1\"} 1",
+            "publicly_render_duration_seconds_bucket{le=\"+Inf\"} 2",
+            "publicly_render_duration_seconds_count 2",
+        ] {
+            assert!(exported.lines().any(|l| l == line), "missing {line:?}");
+        }
+    }
+}
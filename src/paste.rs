@@ -0,0 +1,167 @@
+/// Asks the client terminal to wrap pasted text in the start and end markers.
+pub const ENABLE: &[u8] = b"\x1b[?2004h";
+pub const DISABLE: &[u8] = b"\x1b[?2004l";
+
+const START: &[u8] = b"\x1b[200~";
+const END: &[u8] = b"\x1b[201~";
+
+pub enum Chunk {
+    /// Input typed by the client, to be handled as key presses
+    Keys(Vec<u8>),
+    /// Everything between the paste markers, with the newlines normalized
+    Paste { text: String, truncated: bool },
+}
+
+/// Tracks a bracketed paste for a client, which may arrive over
+/// several data packets.
+#[derive(Default)]
+pub struct Paste {
+    buffer: Option<Vec<u8>>,
+    // Where the paste was cut short once it outgrew the limit
+    cut: Option<usize>,
+    // The end of the last packet, when it may be the beginning of a start marker
+    held: Vec<u8>,
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// The length of the longest proper prefix of the marker the data ends with.
+fn partial_marker(data: &[u8], marker: &[u8]) -> usize {
+    (1..marker.len())
+        .rev()
+        .find(|&len| data.ends_with(&marker[..len]))
+        .unwrap_or(0)
+}
+
+/// Moves the index back to the start of the UTF-8 character it falls in.
+fn char_boundary(data: &[u8], mut index: usize) -> usize {
+    // continuation bytes look like 0b10xxxxxx
+    while index > 0 && index < data.len() && data[index] & 0xc0 == 0x80 {
+        index -= 1;
+    }
+    index
+}
+
+impl Paste {
+    /// Splits the incoming data into key presses and completed pastes.
+    /// Pastes longer than `limit` bytes are cut short at a character boundary.
+    pub fn feed(&mut self, data: &[u8], limit: usize) -> Vec<Chunk> {
+        let mut chunks = vec![];
+        let mut pending = std::mem::take(&mut self.held);
+        pending.extend_from_slice(data);
+
+        while !pending.is_empty() {
+            let Some(buffer) = self.buffer.as_mut() else {
+                let Some(start) = find(&pending, START) else {
+                    // hold on to a start marker split across packets
+                    let keys = pending.len() - partial_marker(&pending, START);
+                    self.held = pending.split_off(keys);
+                    if !pending.is_empty() {
+                        chunks.push(Chunk::Keys(pending));
+                    }
+                    break;
+                };
+                if start > 0 {
+                    chunks.push(Chunk::Keys(pending[..start].to_vec()));
+                }
+                pending.drain(..start + START.len());
+                self.buffer = Some(vec![]);
+                self.cut = None;
+                continue;
+            };
+
+            buffer.append(&mut pending);
+            let Some(end) = find(buffer, END) else {
+                // hold on to enough bytes to spot an end marker split across packets
+                let keep = END.len() - 1;
+                let cut = match self.cut {
+                    Some(cut) => cut,
+                    None if buffer.len() > limit + keep => char_boundary(buffer, limit),
+                    None => break,
+                };
+                buffer.drain(cut..buffer.len() - keep);
+                self.cut = Some(cut);
+                break;
+            };
+
+            pending = buffer.split_off(end + END.len());
+            let len = match self.cut {
+                Some(cut) => cut,
+                None if end > limit => char_boundary(buffer, limit),
+                None => end,
+            };
+            let truncated = self.cut.is_some() || len < end;
+            let pasted = &buffer[..len];
+            let text = String::from_utf8_lossy(pasted)
+                .replace("\r\n", "\n")
+                .replace('\r', "\n");
+            chunks.push(Chunk::Paste { text, truncated });
+            self.buffer = None;
+        }
+        chunks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paste(chunk: &Chunk) -> (&str, bool) {
+        match chunk {
+            Chunk::Paste { text, truncated } => (text, *truncated),
+            Chunk::Keys(keys) => panic!("expected a paste, got keys {keys:?}"),
+        }
+    }
+
+    #[test]
+    fn test_paste_in_one_packet() {
+        let mut state = Paste::default();
+        let chunks = state.feed(b"a\x1b[200~one\rtwo\r\nthree\x1b[201~b", 1024);
+        assert_eq!(chunks.len(), 3);
+        assert!(matches!(&chunks[0], Chunk::Keys(keys) if keys == b"a"));
+        assert_eq!(paste(&chunks[1]), ("one\ntwo\nthree", false));
+        assert!(matches!(&chunks[2], Chunk::Keys(keys) if keys == b"b"));
+    }
+
+    #[test]
+    fn test_paste_across_packets() {
+        let mut state = Paste::default();
+        assert!(state.feed(b"\x1b[200~one\r", 1024).is_empty());
+        assert!(state.feed(b"two\x1b[20", 1024).is_empty());
+        let chunks = state.feed(b"1~", 1024);
+        assert_eq!(paste(&chunks[0]), ("one\ntwo", false));
+    }
+
+    #[test]
+    fn test_paste_limit() {
+        let mut state = Paste::default();
+        state.feed(b"\x1b[200~0123456789", 4);
+        state.feed(b"0123456789\x1b[201", 4);
+        let chunks = state.feed(b"~", 4);
+        assert_eq!(paste(&chunks[0]), ("0123", true));
+    }
+
+    #[test]
+    fn test_start_marker_across_packets() {
+        let mut state = Paste::default();
+        let chunks = state.feed(b"a\x1b[20", 1024);
+        assert!(matches!(&chunks[..], [Chunk::Keys(keys)] if keys == b"a"));
+        assert!(state.feed(b"0~one", 1024).is_empty());
+        let chunks = state.feed(b"\x1b[201~", 1024);
+        assert_eq!(paste(&chunks[0]), ("one", false));
+    }
+
+    #[test]
+    fn test_paste_limit_on_char_boundary() {
+        let mut state = Paste::default();
+        let chunks = state.feed("\x1b[200~aé\x1b[201~".as_bytes(), 2);
+        assert_eq!(paste(&chunks[0]), ("a", true));
+        state.feed("\x1b[200~aéé".as_bytes(), 2);
+        let chunks = state.feed(b"\x1b[201~", 2);
+        assert_eq!(paste(&chunks[0]), ("a", true));
+    }
+}
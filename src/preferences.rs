@@ -0,0 +1,135 @@
+use crate::Error;
+use crate::theme::{self, THEMES, Theme};
+use std::path::{Path, PathBuf};
+
+/// Display preferences of a member, persisted per key fingerprint.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Preferences {
+    pub theme: &'static Theme,
+    pub timestamps: bool,
+    /// Shrinks the input textarea to a single line
+    pub compact: bool,
+}
+
+impl Default for Preferences {
+    fn default() -> Self {
+        Self {
+            theme: &THEMES[0],
+            timestamps: false,
+            compact: false,
+        }
+    }
+}
+
+fn parse_switch(key: &str, value: &str) -> Result<bool, Error> {
+    match value {
+        "on" | "true" | "yes" => Ok(true),
+        "off" | "false" | "no" => Ok(false),
+        _ => Err(Error::InvalidPreferenceValue {
+            key: key.to_string(),
+            value: value.to_string(),
+        }),
+    }
+}
+
+fn switch(value: bool) -> &'static str {
+    if value { "on" } else { "off" }
+}
+
+/// The file holding the preferences for a fingerprint such as `SHA256:abc/d+e`,
+/// with the base64 digest made safe for use as a file name.
+fn path(data_dir: &Path, fingerprint: &str) -> PathBuf {
+    let digest = fingerprint.trim_start_matches("SHA256:");
+    let file_name: String = digest
+        .chars()
+        .map(|c| match c {
+            '/' => '_',
+            '+' => '-',
+            c => c,
+        })
+        .collect();
+    data_dir.join("preferences").join(file_name)
+}
+
+impl Preferences {
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        match key {
+            "theme" => {
+                self.theme =
+                    theme::lookup(value).ok_or_else(|| Error::UnknownTheme(value.to_string()))?;
+            }
+            "timestamps" => self.timestamps = parse_switch(key, value)?,
+            "compact" => self.compact = parse_switch(key, value)?,
+            _ => return Err(Error::UnknownPreference(key.to_string())),
+        }
+        Ok(())
+    }
+
+    /// Loads the preferences for the fingerprint, falling back to
+    /// the defaults for anything missing or unreadable.
+    pub fn load(data_dir: &Path, fingerprint: &str) -> Self {
+        let mut preferences = Self::default();
+        let Ok(contents) = std::fs::read_to_string(path(data_dir, fingerprint)) else {
+            return preferences;
+        };
+        for line in contents.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            if let Err(e) = preferences.set(key.trim(), value.trim()) {
+                log::warn!("ignoring stored preference for {fingerprint}: {e}");
+            }
+        }
+        preferences
+    }
+
+    pub fn save(&self, data_dir: &Path, fingerprint: &str) -> Result<(), Error> {
+        let path = path(data_dir, fingerprint);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(Error::PreferencesNotSaved)?;
+        }
+        std::fs::write(&path, self.to_string()).map_err(Error::PreferencesNotSaved)
+    }
+}
+
+impl std::fmt::Display for Preferences {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "theme = {}", self.theme.name)?;
+        writeln!(f, "timestamps = {}", switch(self.timestamps))?;
+        writeln!(f, "compact = {}", switch(self.compact))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set() {
+        let mut preferences = Preferences::default();
+        preferences.set("theme", "monochrome").unwrap();
+        preferences.set("timestamps", "on").unwrap();
+        assert_eq!(preferences.theme.name, "monochrome");
+        assert!(preferences.timestamps);
+        assert!(preferences.set("theme", "neon").is_err());
+        assert!(preferences.set("compact", "maybe").is_err());
+        assert!(preferences.set("volume", "11").is_err());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let data_dir = std::env::temp_dir().join(format!("publicly-test-{}", std::process::id()));
+        let fingerprint = "SHA256:Ps6A7BicnJXgw9YM1kkN3hmDiuRG5KTD03IQ7czGa/+";
+
+        let mut preferences = Preferences::default();
+        preferences.set("compact", "on").unwrap();
+        preferences.save(&data_dir, fingerprint).unwrap();
+
+        assert_eq!(Preferences::load(&data_dir, fingerprint), preferences);
+        assert_eq!(
+            Preferences::load(&data_dir, "SHA256:unknown"),
+            Preferences::default()
+        );
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+}
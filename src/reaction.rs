@@ -0,0 +1,121 @@
+use crate::Error;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+// Shortcodes accepted in place of typing the emoji itself
+const SHORTCODES: [(&str, &str); 12] = [
+    ("+1", "👍"),
+    ("thumbsup", "👍"),
+    ("-1", "👎"),
+    ("thumbsdown", "👎"),
+    ("heart", "❤️"),
+    ("joy", "😂"),
+    ("smile", "😄"),
+    ("tada", "🎉"),
+    ("eyes", "👀"),
+    ("fire", "🔥"),
+    ("rocket", "🚀"),
+    ("check", "✅"),
+];
+
+// The most characters a reaction can have, enough for emoji with modifiers
+const MAX_REACTION_CHARS: usize = 8;
+
+/// An emoji attached to a message, parsed from either the emoji itself
+/// or a shortcode such as `:tada:`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Emoji(String);
+
+impl Emoji {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for Emoji {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let shortcode = s.trim_matches(':');
+        if let Some((_, emoji)) = SHORTCODES.iter().find(|(code, _)| *code == shortcode) {
+            return Ok(Emoji(emoji.to_string()));
+        }
+
+        // anything else must look like an emoji: short, printable and not plain ASCII
+        let plausible = !s.is_empty()
+            && s.chars().count() <= MAX_REACTION_CHARS
+            && s.chars().all(|c| !c.is_ascii() && !c.is_control());
+        if !plausible {
+            return Err(Error::InvalidReaction(s.to_string()));
+        }
+        Ok(Emoji(s.to_string()))
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct Reactor {
+    pub name: String,
+    pub fingerprint: String,
+}
+
+/// All reactions of a single kind on a message.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct Reaction {
+    pub emoji: Emoji,
+    pub reactors: Vec<Reactor>,
+}
+
+/// Adds the reactor to the reaction with the given emoji or removes them
+/// if they had already reacted with it.
+pub(crate) fn toggle(reactions: &mut Vec<Reaction>, emoji: Emoji, reactor: Reactor) {
+    let Some(reaction) = reactions.iter_mut().find(|r| r.emoji == emoji) else {
+        reactions.push(Reaction {
+            emoji,
+            reactors: vec![reactor],
+        });
+        return;
+    };
+
+    let before = reaction.reactors.len();
+    reaction
+        .reactors
+        .retain(|r| r.fingerprint != reactor.fingerprint);
+    if reaction.reactors.len() == before {
+        reaction.reactors.push(reactor);
+    }
+    reactions.retain(|r| !r.reactors.is_empty());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reactor(fingerprint: &str) -> Reactor {
+        Reactor {
+            name: fingerprint.to_string(),
+            fingerprint: fingerprint.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse_emoji() {
+        assert_eq!(":tada:".parse::<Emoji>().unwrap().as_str(), "🎉");
+        assert_eq!("+1".parse::<Emoji>().unwrap().as_str(), "👍");
+        assert_eq!("🦀".parse::<Emoji>().unwrap().as_str(), "🦀");
+        assert!("lol".parse::<Emoji>().is_err());
+        assert!("\u{1b}[2J".parse::<Emoji>().is_err());
+    }
+
+    #[test]
+    fn test_toggle_reaction() {
+        let mut reactions = vec![];
+        let tada: Emoji = "tada".parse().unwrap();
+        toggle(&mut reactions, tada.clone(), reactor("SHA256:a"));
+        toggle(&mut reactions, tada.clone(), reactor("SHA256:b"));
+        assert_eq!(reactions[0].reactors.len(), 2);
+
+        toggle(&mut reactions, tada.clone(), reactor("SHA256:a"));
+        toggle(&mut reactions, tada, reactor("SHA256:b"));
+        assert!(reactions.is_empty());
+    }
+}
@@ -0,0 +1,342 @@
+use crate::history::{Entry, History, MessageId};
+use crate::invite::Invites;
+use crate::message::{Chat, Message};
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::io::{Read, Write};
+use std::net::IpAddr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+use tokio::signal::unix::{Signal, SignalKind, signal};
+
+// Environment variables through which the new process finds the listening socket and the state
+const LISTENER_FD_VAR: &str = "PUBLICLY_LISTENER_FD";
+const STATE_VAR: &str = "PUBLICLY_STATE";
+// The pipe the new process reports on once it is serving
+const READY_FD_VAR: &str = "PUBLICLY_READY_FD";
+
+// How long the new process has to start serving before it is given up on
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+// The file in the data directory the state is handed over in
+const STATE_FILE: &str = "restart.json";
+
+/// What a new server process carries over from the one it replaces.
+#[derive(Serialize, Deserialize)]
+pub struct State {
+    /// The keychain in the Authfile format, including changes not yet committed
+    pub keychain: String,
+    pub history: Vec<SavedEntry>,
+    pub next_id: MessageId,
+    /// Locked out addresses along with how long they remain blocked
+    pub blocked: Vec<(IpAddr, Duration)>,
+    /// What the Authfile format has no room for, by key fingerprint
+    // defaults let a state written by an older version be read
+    #[serde(default)]
+    pub personas: Vec<SavedPersona>,
+    #[serde(default)]
+    pub invites: Invites,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SavedPersona {
+    pub fingerprint: String,
+    pub nicked_at: Option<SystemTime>,
+    pub locked: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SavedEntry {
+    id: MessageId,
+    sent_at: SystemTime,
+    message: SavedMessage,
+}
+
+#[derive(Serialize, Deserialize)]
+enum SavedMessage {
+    Chat(Chat),
+    Notice(String),
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("failed to write the state to {}", path.display())]
+    StateNotWritten {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+    #[error("failed to read the state handed over in {}", path.display())]
+    StateNotRead {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+    #[error("the state handed over in {} is invalid", path.display())]
+    InvalidState {
+        source: serde_json::Error,
+        path: PathBuf,
+    },
+    #[error("failed to share the listening socket with the new process")]
+    ListenerNotShared(#[source] std::io::Error),
+    #[error("invalid listening socket {0:?} handed over")]
+    InvalidListener(String),
+    #[error("failed to start the new process")]
+    NotStarted(#[source] std::io::Error),
+    #[error("the new process did not start serving: {0}")]
+    NotReady(String),
+}
+
+/// Saves the history entries that outlive the sessions of this process.
+/// Announcements keep their text as it reads now and dossiers are left out,
+/// as the sessions they were requested by are gone.
+pub async fn save_history(history: &History) -> (Vec<SavedEntry>, MessageId) {
+    let mut saved = vec![];
+    for Entry {
+        id,
+        sent_at,
+        message,
+    } in history.to_vec()
+    {
+        let message = match message {
+            Message::Chat(chat) => SavedMessage::Chat(chat),
+            Message::Notice(notice) => SavedMessage::Notice(notice),
+            Message::Announce { action, persona } => {
+                SavedMessage::Notice(action.describe(&*persona.read().await))
+            }
+            Message::Dossier { .. } => continue,
+        };
+        saved.push(SavedEntry {
+            id,
+            sent_at,
+            message,
+        });
+    }
+    (saved, history.next_id())
+}
+
+pub fn restore_history(capacity: usize, entries: Vec<SavedEntry>, next_id: MessageId) -> History {
+    let entries = entries
+        .into_iter()
+        .map(|entry| Entry {
+            id: entry.id,
+            sent_at: entry.sent_at,
+            message: match entry.message {
+                SavedMessage::Chat(chat) => Message::Chat(chat),
+                SavedMessage::Notice(notice) => Message::Notice(notice),
+            },
+        })
+        .collect();
+    History::restore(capacity, entries, next_id)
+}
+
+/// Starts a new process of the current program with the same arguments,
+/// handing it the listening socket and the state, and waits for it to start
+/// serving. Returns the id of the new process.
+pub async fn hand_over(
+    listener: &tokio::net::TcpListener,
+    state: &State,
+    data_dir: &Path,
+) -> Result<u32, Error> {
+    let path = data_dir.join(STATE_FILE);
+    let written = serde_json::to_vec(state)
+        .map_err(std::io::Error::other)
+        .and_then(|contents| {
+            // the state holds the chat history, which is for members only
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(&path)?
+                .write_all(&contents)
+        });
+    if let Err(source) = written {
+        return Err(Error::StateNotWritten { source, path });
+    }
+
+    let started = std::io::pipe()
+        .map_err(Error::NotStarted)
+        .and_then(|(ready, reporter)| {
+            let fd = inheritable(&reporter).map_err(Error::NotStarted)?;
+            let listener_fd = inheritable(listener).map_err(Error::ListenerNotShared)?;
+            let mut args = std::env::args_os();
+            // the path the program was started with leads to the new binary after an upgrade
+            let program = args.next().unwrap_or_else(|| OsString::from("publicly"));
+            let child = std::process::Command::new(program)
+                .args(args)
+                .env(LISTENER_FD_VAR, listener_fd.as_raw_fd().to_string())
+                .env(STATE_VAR, &path)
+                .env(READY_FD_VAR, fd.as_raw_fd().to_string())
+                // the watchdog of systemd is kept alive by the new process from now on
+                .env_remove("WATCHDOG_PID")
+                .spawn()
+                .map_err(Error::NotStarted)?;
+            Ok((child, ready))
+        });
+    let (mut child, mut ready) = match started {
+        Ok(started) => started,
+        Err(e) => {
+            let _ = std::fs::remove_file(&path);
+            return Err(e);
+        }
+    };
+
+    // only the new process holds the other end now, which closes should it exit
+    let reported = tokio::task::spawn_blocking(move || ready.read(&mut [0]));
+    let failure = match tokio::time::timeout(READY_TIMEOUT, reported).await {
+        Ok(Ok(Ok(1))) => return Ok(child.id()),
+        Ok(Ok(Ok(_))) => "it exited before reporting".to_string(),
+        Ok(Ok(Err(e))) => format!("failed to wait for its report: {e}"),
+        Ok(Err(e)) => format!("failed to wait for its report: {e}"),
+        Err(_) => format!("it did not report within {}s", READY_TIMEOUT.as_secs()),
+    };
+    // two processes must not serve at once
+    let _ = child.kill();
+    let status = child.wait();
+    let _ = std::fs::remove_file(&path);
+    match status {
+        Ok(status) => Err(Error::NotReady(format!("{failure}, {status}"))),
+        Err(_) => Err(Error::NotReady(failure)),
+    }
+}
+
+/// Duplicates the descriptor without the close-on-exec flag, so that a new
+/// process inherits it. The duplicate is closed once dropped.
+fn inheritable(fd: &impl AsRawFd) -> std::io::Result<OwnedFd> {
+    // SAFETY: dup only creates a new descriptor for the open one
+    let duplicate = unsafe { libc::dup(fd.as_raw_fd()) };
+    if duplicate < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // SAFETY: the descriptor was just created and is owned by nothing else
+    Ok(unsafe { OwnedFd::from_raw_fd(duplicate) })
+}
+
+/// Tells the process this one replaces that it is now serving.
+pub fn report_ready() {
+    let Some(fd) = std::env::var(READY_FD_VAR)
+        .ok()
+        .and_then(|value| value.parse::<RawFd>().ok())
+    else {
+        return;
+    };
+    // SAFETY: the previous process passed the pipe under this number
+    // and nothing else in this process refers to it
+    let mut pipe = std::fs::File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+    if let Err(e) = pipe.write_all(b"1") {
+        log::error!("failed to report to the previous process: {e}");
+    }
+}
+
+/// The listening socket handed over by the process this one replaces.
+pub fn inherited_listener() -> Result<Option<std::net::TcpListener>, Error> {
+    let Ok(value) = std::env::var(LISTENER_FD_VAR) else {
+        return Ok(None);
+    };
+    let fd: RawFd = value
+        .parse()
+        .map_err(|_| Error::InvalidListener(value.clone()))?;
+    // SAFETY: the previous process passed an open socket under this number
+    // and nothing else in this process refers to it
+    let listener = unsafe { adopt_listener(fd) }.map_err(Error::ListenerNotShared)?;
+    Ok(Some(listener))
+}
+
+/// Takes ownership of a listening socket inherited from another process,
+/// keeping it from leaking into processes started later on.
+///
+/// # Safety
+///
+/// The descriptor must be an open socket not owned by anything else in this process.
+pub unsafe fn adopt_listener(fd: RawFd) -> std::io::Result<std::net::TcpListener> {
+    // SAFETY: upheld by the caller
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    // SAFETY: only the descriptor flags of the socket owned above are changed
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Reads and removes the state handed over by the process this one replaces.
+pub fn take_state() -> Result<Option<State>, Error> {
+    let Some(path) = std::env::var_os(STATE_VAR).map(PathBuf::from) else {
+        return Ok(None);
+    };
+    let contents = match std::fs::read(&path) {
+        Ok(contents) => contents,
+        Err(source) => return Err(Error::StateNotRead { source, path }),
+    };
+    if let Err(e) = std::fs::remove_file(&path) {
+        log::warn!(
+            "failed to remove the handed over state {}: {e}",
+            path.display()
+        );
+    }
+    serde_json::from_slice(&contents)
+        .map(Some)
+        .map_err(|source| Error::InvalidState { source, path })
+}
+
+/// The signal asking the process to restart, SIGUSR2.
+pub struct Signals(Signal);
+
+impl Signals {
+    /// Starts listening for the signal, none sent from now on are missed.
+    pub fn new() -> std::io::Result<Self> {
+        Ok(Self(signal(SignalKind::user_defined2())?))
+    }
+
+    /// Waits for the process to be asked to restart.
+    pub async fn requested(&mut self) {
+        self.0.recv().await;
+        log::info!("received SIGUSR2");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entity::{Entity, Role};
+    use crate::message::Announcement;
+    use russh::keys::PublicKey;
+
+    #[tokio::test]
+    async fn test_history_round_trip() {
+        let mut history = History::new(8);
+        let key = PublicKey::from_openssh(
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIA/wbGoIUsbBHFbnXj2g+23C8sUgYkZTq0TrBm0MMWnx",
+        )
+        .unwrap();
+        let persona = Entity::new(key, "bob", Role::Normal).persona();
+        history.enqueue(Message::Announce {
+            action: Announcement::Joined,
+            persona,
+        });
+        history.enqueue(Message::Dossier {
+            contents: "for one admin only".to_string(),
+            requested_by: 1,
+        });
+        let chat = Chat::new("bob".to_string(), "SHA256:a".to_string(), "hi".to_string());
+        history.enqueue(Message::Chat(chat));
+
+        let (entries, next_id) = save_history(&history).await;
+        let json = serde_json::to_string(&entries).unwrap();
+        let entries: Vec<SavedEntry> = serde_json::from_str(&json).unwrap();
+        let mut restored = restore_history(8, entries, next_id);
+
+        let restored_entries = restored.to_vec();
+        assert_eq!(restored_entries.len(), 2);
+        assert!(matches!(
+            &restored_entries[0].message,
+            Message::Notice(notice) if notice == "bob has joined the chat with normal privileges"
+        ));
+        assert!(matches!(&restored_entries[1].message, Message::Chat(chat) if chat.text == "hi"));
+        assert_eq!(restored_entries[1].id, 3);
+        // new messages carry on from the IDs of the previous process
+        assert_eq!(restored.enqueue(Message::Notice(String::new())), 4);
+    }
+}
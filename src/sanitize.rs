@@ -0,0 +1,171 @@
+//! Every user-supplied string is drawn on the terminal of every member,
+//! so it passes through here before it is stored or rendered.
+
+const ESC: char = '\u{1b}';
+const BEL: char = '\u{7}';
+const ZERO_WIDTH_JOINER: char = '\u{200d}';
+const TAB_WIDTH: usize = 4;
+
+/// Characters that are invisible or reorder text, which can be used
+/// to disguise the contents of a message or a name.
+fn is_deceptive(c: char) -> bool {
+    matches!(
+        c,
+        // bidirectional marks, embeddings, overrides and isolates
+        '\u{061c}' | '\u{200e}' | '\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}'
+        // zero width spaces, non-joiners, word joiners and invisible operators
+        | '\u{200b}' | '\u{200c}' | '\u{2060}'..='\u{2064}' | '\u{180e}' | '\u{feff}'
+    )
+}
+
+/// Skips the parameters and intermediates of a control sequence up to its final byte.
+fn skip_csi<I: Iterator<Item = char>>(chars: &mut I) {
+    for c in chars.by_ref() {
+        if ('@'..='~').contains(&c) {
+            break;
+        }
+    }
+}
+
+/// Skips the rest of an escape sequence whose ESC has already been consumed.
+fn skip_escape<I: Iterator<Item = char>>(chars: &mut std::iter::Peekable<I>) {
+    match chars.peek() {
+        Some('[') => {
+            chars.next();
+            skip_csi(chars);
+        }
+        // OSC, DCS, SOS, PM and APC: strings terminated by BEL or ST
+        Some(']' | 'P' | 'X' | '^' | '_') => {
+            chars.next();
+            while let Some(c) = chars.next() {
+                if c == BEL {
+                    break;
+                }
+                if c == ESC && chars.peek() == Some(&'\\') {
+                    chars.next();
+                    break;
+                }
+            }
+        }
+        // two character sequences such as ESC c, which resets the terminal
+        Some(_) => {
+            chars.next();
+        }
+        None => {}
+    }
+}
+
+/// Sanitises free-form text such as chat messages, keeping newlines.
+/// Escape sequences are dropped whole, tabs are expanded to spaces and
+/// other control, bidirectional and zero-width characters are removed.
+pub fn text(s: &str) -> String {
+    let mut sanitized = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    let mut previous = None;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => sanitized.push(c),
+            '\t' => sanitized.extend(std::iter::repeat_n(' ', TAB_WIDTH)),
+            ESC => skip_escape(&mut chars),
+            // the C1 control sequence introducer, equivalent to ESC [
+            '\u{9b}' => skip_csi(&mut chars),
+            // joiners are only legitimate inside emoji sequences
+            ZERO_WIDTH_JOINER => {
+                let joins_symbols =
+                    previous.is_some_and(is_symbol) && chars.peek().copied().is_some_and(is_symbol);
+                if joins_symbols {
+                    sanitized.push(c);
+                }
+            }
+            // C0 controls, DEL and C1 controls
+            c if c.is_control() || is_deceptive(c) => {}
+            c => sanitized.push(c),
+        }
+        previous = sanitized.chars().last();
+    }
+    sanitized
+}
+
+fn is_symbol(c: char) -> bool {
+    c >= '\u{2000}' && !c.is_whitespace()
+}
+
+/// Sanitises text that must fit on a single line, such as the statusline.
+pub fn line(s: &str) -> String {
+    text(s).replace('\n', " ")
+}
+
+/// Usernames may only contain ASCII alphanumeric characters and the symbols `@-_.`.
+pub fn name(s: &str) -> String {
+    let mut sanitized = String::with_capacity(s.len());
+    for c in s.chars() {
+        let ok = c.is_ascii_alphanumeric() || "@_-.".contains(c);
+        if !ok {
+            continue;
+        }
+        sanitized.push(c);
+    }
+    sanitized
+}
+
+/// Whether two names are too alike to tell their members apart,
+/// which is when they differ only in case.
+pub fn names_collide(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strips_escape_sequences() {
+        let hostile = [
+            ("\u{1b}[2Jcleared", "cleared"),
+            ("\u{1b}[38;2;255;0;0mred\u{1b}[0m", "red"),
+            ("\u{1b}]0;pwned\u{7}title", "title"),
+            (
+                "\u{1b}]8;;http://evil\u{1b}\\link\u{1b}]8;;\u{1b}\\",
+                "link",
+            ),
+            ("\u{1b}creset", "reset"),
+            ("\u{9b}2Jc1", "c1"),
+            ("dangling\u{1b}", "dangling"),
+        ];
+        for (input, expected) in hostile {
+            assert_eq!(text(input), expected, "input: {input:?}");
+        }
+    }
+
+    #[test]
+    fn test_strips_controls() {
+        assert_eq!(text("a\u{0}b\u{7}c\u{8}d\re\u{7f}f\u{85}g"), "abcdefg");
+        assert_eq!(text("one\ntwo\tthree"), "one\ntwo    three");
+        assert_eq!(line("one\ntwo"), "one two");
+    }
+
+    #[test]
+    fn test_strips_bidi_and_zero_width() {
+        assert_eq!(text("evil\u{202e}txt.exe"), "eviltxt.exe");
+        assert_eq!(text("\u{2066}iso\u{2069}"), "iso");
+        assert_eq!(text("ad\u{200b}m\u{200c}i\u{2060}n\u{feff}"), "admin");
+        assert_eq!(text("a\u{200d}b"), "ab");
+        // emoji sequences keep their joiners
+        assert_eq!(text("👩\u{200d}💻"), "👩\u{200d}💻");
+    }
+
+    #[test]
+    fn test_keeps_ordinary_text() {
+        let ordinary = "héllo wörld, 你好 🦀 *bold* `code`";
+        assert_eq!(text(ordinary), ordinary);
+    }
+
+    #[test]
+    fn test_name() {
+        assert_eq!(name("h@cafe\u{1b}[2J"), "h@cafe2J");
+        assert_eq!(name("bob work"), "bobwork");
+        assert!(names_collide("Admin", "admin"));
+        assert!(!names_collide("admin", "admin2"));
+    }
+}
@@ -0,0 +1,72 @@
+use std::time::Duration;
+use tokio::signal::unix::{Signal, SignalKind, signal};
+
+/// A request to stop the server, made with `/shutdown` or by a signal.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Shutdown {
+    /// How long members have to wrap up before they are disconnected
+    pub delay: Duration,
+    pub reason: Option<String>,
+    /// Whether a new process is taking over, so members may reconnect straight away
+    pub restart: bool,
+}
+
+impl Shutdown {
+    /// The notice shown to every connected session.
+    pub fn notice(&self) -> String {
+        if self.restart {
+            return "the server is restarting, reconnect to continue".to_string();
+        }
+        let mut notice = "the server is shutting down".to_string();
+        if !self.delay.is_zero() {
+            notice.push_str(&format!(" in {}s", self.delay.as_secs()));
+        }
+        if let Some(reason) = &self.reason {
+            notice.push_str(&format!(": {reason}"));
+        }
+        notice
+    }
+}
+
+/// The signals asking the process to stop, SIGTERM and SIGINT.
+pub struct Signals {
+    terminate: Signal,
+    interrupt: Signal,
+}
+
+impl Signals {
+    /// Starts listening for the signals, none sent from now on are missed.
+    pub fn new() -> std::io::Result<Self> {
+        Ok(Self {
+            terminate: signal(SignalKind::terminate())?,
+            interrupt: signal(SignalKind::interrupt())?,
+        })
+    }
+
+    /// Waits for the process to be asked to stop.
+    pub async fn requested(&mut self) {
+        tokio::select! {
+            _ = self.terminate.recv() => log::info!("received SIGTERM"),
+            _ = self.interrupt.recv() => log::info!("received SIGINT"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notice() {
+        assert_eq!(Shutdown::default().notice(), "the server is shutting down");
+        let shutdown = Shutdown {
+            delay: Duration::from_secs(300),
+            reason: Some("upgrading".to_string()),
+            restart: false,
+        };
+        assert_eq!(
+            shutdown.notice(),
+            "the server is shutting down in 300s: upgrading"
+        );
+    }
+}
@@ -0,0 +1,75 @@
+use crate::restart::adopt_listener;
+use std::os::fd::RawFd;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+use thiserror::Error;
+
+// The first socket passed with socket activation, see sd_listen_fds(3)
+const LISTEN_FDS_START: RawFd = 3;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("invalid LISTEN_FDS {0:?}")]
+    InvalidListenFds(String),
+    #[error("failed to use the socket passed by systemd")]
+    InvalidListener(#[source] std::io::Error),
+}
+
+/// Whether a variable set by systemd is meant for this process rather than
+/// one it was inherited from.
+fn for_this_process(var: &str) -> bool {
+    std::env::var(var).is_ok_and(|pid| pid.parse() == Ok(std::process::id()))
+}
+
+/// The listening socket passed by systemd with socket activation.
+pub fn listener() -> Result<Option<std::net::TcpListener>, Error> {
+    let Ok(count) = std::env::var("LISTEN_FDS") else {
+        return Ok(None);
+    };
+    if !for_this_process("LISTEN_PID") {
+        return Ok(None);
+    }
+    let count: u32 = count
+        .parse()
+        .map_err(|_| Error::InvalidListenFds(count.clone()))?;
+    if count == 0 {
+        return Ok(None);
+    }
+    if count > 1 {
+        log::warn!("systemd passed {count} sockets, only the first is used");
+    }
+    // SAFETY: systemd passes the sockets from descriptor 3 onwards
+    // and nothing else in this process refers to them
+    let listener = unsafe { adopt_listener(LISTEN_FDS_START) }.map_err(Error::InvalidListener)?;
+    Ok(Some(listener))
+}
+
+/// Tells systemd about a change in the state of the server, such as `READY=1`,
+/// when it was started as a `Type=notify` service. See sd_notify(3).
+pub fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let sent =
+        UnixDatagram::unbound().and_then(|socket| match path.as_bytes().strip_prefix(b"@") {
+            Some(name) => {
+                socket.send_to_addr(state.as_bytes(), &SocketAddr::from_abstract_name(name)?)
+            }
+            None => socket.send_to(state.as_bytes(), &path),
+        });
+    if let Err(e) = sent {
+        log::warn!("failed to notify systemd of {state}: {e}");
+    }
+}
+
+/// How often systemd expects `WATCHDOG=1`, half of the configured `WatchdogSec`
+/// to leave room for delays, or `None` when the watchdog is not enabled.
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if std::env::var_os("WATCHDOG_PID").is_some() && !for_this_process("WATCHDOG_PID") {
+        return None;
+    }
+    Some(Duration::from_micros(usec) / 2)
+}
@@ -0,0 +1,46 @@
+use ratatui::style::{Color, Modifier, Style};
+
+/// The styles used to render the chat interface.
+#[derive(Debug, PartialEq)]
+pub struct Theme {
+    pub name: &'static str,
+    pub announcement: Style,
+    pub dossier: Style,
+    /// Message IDs, quotes, reactors and other secondary text
+    pub dim: Style,
+    pub code: Style,
+}
+
+pub static THEMES: [Theme; 3] = [
+    Theme {
+        name: "default",
+        announcement: Style::new().fg(Color::Green),
+        dossier: Style::new().fg(Color::LightCyan),
+        dim: Style::new().fg(Color::DarkGray),
+        code: Style::new().fg(Color::White).bg(Color::DarkGray),
+    },
+    Theme {
+        name: "ocean",
+        announcement: Style::new().fg(Color::Cyan),
+        dossier: Style::new().fg(Color::LightBlue),
+        dim: Style::new().fg(Color::Blue),
+        code: Style::new().fg(Color::LightCyan).bg(Color::Blue),
+    },
+    // Relies on modifiers alone for terminals without colour or for readers
+    // who need high contrast
+    Theme {
+        name: "monochrome",
+        announcement: Style::new().add_modifier(Modifier::BOLD),
+        dossier: Style::new().add_modifier(Modifier::ITALIC),
+        dim: Style::new(),
+        code: Style::new().add_modifier(Modifier::REVERSED),
+    },
+];
+
+pub fn lookup(name: &str) -> Option<&'static Theme> {
+    THEMES.iter().find(|theme| theme.name == name)
+}
+
+pub fn names() -> Vec<&'static str> {
+    THEMES.iter().map(|theme| theme.name).collect()
+}
@@ -3,20 +3,41 @@ use std::rc::Rc;
 use ratatui::{
     Frame,
     layout::{Constraint, Direction, Layout, Rect},
-    widgets::Clear,
+    widgets::{Block, Clear},
 };
 
+use crate::capabilities::Capabilities;
+
 const UI_LAYOUT: [ratatui::layout::Constraint; 3] = [
     Constraint::Fill(1),   // message history
     Constraint::Length(4), // input textarea
     Constraint::Length(1), // statusline
 ];
 
-pub fn layout(f: &mut Frame) -> Rc<[Rect]> {
+// Leaves room for a single line of input within the textarea borders
+const COMPACT_UI_LAYOUT: [ratatui::layout::Constraint; 3] = [
+    Constraint::Fill(1),   // message history
+    Constraint::Length(3), // input textarea
+    Constraint::Length(1), // statusline
+];
+
+pub fn layout(f: &mut Frame, compact: bool) -> Rc<[Rect]> {
     f.render_widget(Clear, f.area());
 
+    let constraints = if compact {
+        COMPACT_UI_LAYOUT
+    } else {
+        UI_LAYOUT
+    };
     Layout::default()
         .direction(Direction::Vertical)
-        .constraints(UI_LAYOUT)
+        .constraints(constraints)
         .split(f.area())
 }
+
+/// The border around the input textarea, titled with the member's name and role.
+pub fn textarea_block(title: String, capabilities: &Capabilities) -> Block<'static> {
+    Block::bordered()
+        .border_set(capabilities.border_set())
+        .title(title)
+}
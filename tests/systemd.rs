@@ -0,0 +1,73 @@
+use std::io::Read;
+use std::net::{TcpListener, TcpStream};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn receive(notify: &UnixDatagram) -> String {
+    let mut buffer = [0; 256];
+    let len = notify.recv(&mut buffer).expect("no notification");
+    String::from_utf8_lossy(&buffer[..len]).into_owned()
+}
+
+/// Starts the server the way systemd does for a `Type=notify` service
+/// with socket activation and stops it with SIGTERM.
+#[test]
+fn test_socket_activation() {
+    let dir = std::env::temp_dir().join(format!("publicly-systemd-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let notify_path = dir.join("notify");
+    let notify = UnixDatagram::bind(&notify_path).unwrap();
+    notify.set_read_timeout(Some(TIMEOUT)).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let fd = listener.as_raw_fd();
+
+    let mut command = Command::new("sh");
+    // LISTEN_PID names the service process, which the shell becomes with exec
+    command
+        .args(["-c", "export LISTEN_PID=$$; exec \"$0\" \"$@\""])
+        .arg(env!("CARGO_BIN_EXE_publicly"))
+        .args(["--authfile", "tests/fixtures/valid_authfile", "--data-dir"])
+        .arg(&dir)
+        // the port would be taken by the socket, were it bound instead of passed
+        .args(["--port", &port.to_string()])
+        .env("LISTEN_FDS", "1")
+        .env("NOTIFY_SOCKET", &notify_path)
+        .stderr(Stdio::null());
+    // SAFETY: only async-signal-safe calls are made between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            // passed sockets start at descriptor 3 and must survive exec
+            let result = if fd == 3 {
+                libc::fcntl(fd, libc::F_SETFD, 0)
+            } else {
+                libc::dup2(fd, 3)
+            };
+            if result < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut server = command.spawn().unwrap();
+    drop(listener);
+
+    assert_eq!(receive(&notify), "READY=1");
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let mut banner = [0; 8];
+    stream.read_exact(&mut banner).unwrap();
+    assert_eq!(&banner, b"SSH-2.0-");
+
+    // SAFETY: the signal goes to the child started above
+    unsafe { libc::kill(server.id() as libc::pid_t, libc::SIGTERM) };
+    assert_eq!(receive(&notify), "STOPPING=1");
+    assert!(server.wait().unwrap().success());
+    std::fs::remove_dir_all(&dir).unwrap();
+}